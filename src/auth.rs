@@ -0,0 +1,251 @@
+use anyhow::Result;
+use rouille::Request;
+
+use crate::config::Config;
+use crate::db::Database;
+use crate::users::User;
+
+/// Cookie holding the token which forms must submit back, proving they came from a vidl page
+pub const CSRF_COOKIE: &str = "vidl_csrf";
+
+/// Form field the CSRF token is submitted in
+pub const CSRF_FIELD: &str = "csrf";
+
+/// Credentials accepted by the web server, in addition to any user accounts
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct WebAuth {
+    /// Login accepted via HTTP basic authentication
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// API tokens, sent as `Authorization: Bearer <token>` or a `token` query parameter for
+    /// clients such as feed readers which cannot set headers
+    #[serde(default)]
+    pub tokens: Vec<String>,
+}
+
+impl WebAuth {
+    /// Whether any credentials are configured, meaning anonymous requests are rejected
+    pub fn enabled(&self) -> bool {
+        self.username.is_some() || !self.tokens.is_empty()
+    }
+
+    pub fn accepts_basic(&self, username: &str, password: &str) -> bool {
+        match (&self.username, &self.password) {
+            (Some(u), Some(p)) => constant_time_eq(u, username) & constant_time_eq(p, password),
+            _ => false,
+        }
+    }
+
+    pub fn accepts_token(&self, token: &str) -> bool {
+        self.tokens
+            .iter()
+            .fold(false, |acc, t| acc | constant_time_eq(t, token))
+    }
+}
+
+/// Compare secrets without returning early, so response times don't reveal how much of a
+/// guess was correct
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// Who a request was made by
+pub enum Auth {
+    /// No credentials or accounts are configured, so everyone has full access and all state
+    /// is global
+    Open,
+    /// Logged into a user account
+    User(User),
+    /// Authenticated with the configured basic login or an API token, giving full access with
+    /// state being global
+    Service,
+    /// No valid credentials. `accounts` is set if user accounts exist, so browsers can be sent
+    /// to the login form
+    Anonymous { accounts: bool },
+}
+
+impl Auth {
+    pub fn user(&self) -> Option<&User> {
+        match self {
+            Auth::User(u) => Some(u),
+            _ => None,
+        }
+    }
+
+    /// Whether the admin pages are available. Without any credentials configured they are
+    /// not, as anyone who can reach the server could otherwise take it over
+    pub fn is_admin(&self) -> bool {
+        match self {
+            Auth::Service => true,
+            Auth::User(u) => u.admin,
+            Auth::Open | Auth::Anonymous { .. } => false,
+        }
+    }
+}
+
+fn cookie(request: &Request, name: &str) -> Option<String> {
+    rouille::input::cookies(request)
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v.to_string())
+}
+
+pub fn session_token(request: &Request) -> Option<String> {
+    cookie(request, crate::users::SESSION_COOKIE)
+}
+
+/// API token from the `Authorization` header or `token` query parameter
+pub fn api_token(request: &Request) -> Option<String> {
+    let header = request
+        .header("Authorization")
+        .filter(|h| h.starts_with("Bearer "))
+        .map(|h| h["Bearer ".len()..].trim().to_string());
+    header.or_else(|| request.get_param("token"))
+}
+
+pub fn authenticate(request: &Request, cfg: &Config, db: &Database) -> Result<Auth> {
+    let accounts = crate::users::any_users(db)?;
+    if !accounts && !cfg.web_auth.enabled() {
+        return Ok(Auth::Open);
+    }
+
+    let basic = rouille::input::basic_http_auth(request);
+    let auth = check_credentials(
+        &cfg.web_auth,
+        api_token(request).as_deref(),
+        basic
+            .as_ref()
+            .map(|c| (c.login.as_str(), c.password.as_str())),
+        || match session_token(request) {
+            Some(token) => User::from_session(db, &token),
+            None => Ok(None),
+        },
+    )?;
+    Ok(auth.unwrap_or(Auth::Anonymous { accounts }))
+}
+
+/// Try each way of authenticating in turn, so credentials which are present but not accepted
+/// (such as a stale token in a feed URL) do not stop a logged in user being recognised
+fn check_credentials(
+    web_auth: &WebAuth,
+    token: Option<&str>,
+    basic: Option<(&str, &str)>,
+    session: impl FnOnce() -> Result<Option<User>>,
+) -> Result<Option<Auth>> {
+    if token.is_some_and(|t| web_auth.accepts_token(t)) {
+        return Ok(Some(Auth::Service));
+    }
+    if basic.is_some_and(|(u, p)| web_auth.accepts_basic(u, p)) {
+        return Ok(Some(Auth::Service));
+    }
+    Ok(session()?.map(Auth::User))
+}
+
+/// Whether a request can skip the CSRF check, which is only when it was authenticated by an
+/// accepted API token. Browsers never send those automatically, but a `token` parameter alone
+/// proves nothing if no credentials are configured
+pub fn csrf_exempt(auth: &Auth, web_auth: &WebAuth, token: Option<&str>) -> bool {
+    match (auth, token) {
+        (Auth::Service, Some(token)) => web_auth.accepts_token(token),
+        _ => false,
+    }
+}
+
+pub fn csrf_cookie(request: &Request) -> Option<String> {
+    cookie(request, CSRF_COOKIE)
+}
+
+/// Whether a form was submitted with the token from the browser's CSRF cookie. Another site
+/// can make the browser send the cookie but cannot read it, so cannot submit a matching token
+pub fn check_csrf(request: &Request, submitted: Option<&str>) -> bool {
+    match (csrf_cookie(request), submitted) {
+        (Some(expected), Some(submitted)) => constant_time_eq(&expected, submitted),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_admin() {
+        assert!(!Auth::Open.is_admin());
+        assert!(Auth::Service.is_admin());
+        assert!(!Auth::Anonymous { accounts: true }.is_admin());
+    }
+
+    #[test]
+    fn test_check_credentials() -> Result<()> {
+        let auth: WebAuth =
+            serde_json::from_str(r#"{"username": "admin", "password": "pw", "tokens": ["abc"]}"#)?;
+        let db = Database::open_in_memory()?;
+        let user = || User::create(&db, "someone", "hunter2", false).map(Some);
+        let none = || Ok(None);
+
+        let found = |token, basic, session: &dyn Fn() -> Result<Option<User>>| {
+            check_credentials(&auth, token, basic, session)
+        };
+        assert!(matches!(
+            found(Some("abc"), None, &none)?,
+            Some(Auth::Service)
+        ));
+        assert!(matches!(
+            found(None, Some(("admin", "pw")), &none)?,
+            Some(Auth::Service)
+        ));
+        assert!(found(Some("bogus"), Some(("admin", "wrong")), &none)?.is_none());
+
+        // Wrong token or basic login still falls through to the session
+        assert!(matches!(
+            found(Some("bogus"), Some(("admin", "wrong")), &user)?,
+            Some(Auth::User(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_csrf_exempt() {
+        let auth: WebAuth = serde_json::from_str(r#"{"tokens": ["abc"]}"#).unwrap();
+        assert!(csrf_exempt(&Auth::Service, &auth, Some("abc")));
+        // Bogus token with no credentials configured, or when logged in another way
+        assert!(!csrf_exempt(
+            &Auth::Open,
+            &WebAuth::default(),
+            Some("bogus")
+        ));
+        assert!(!csrf_exempt(&Auth::Service, &auth, Some("bogus")));
+        assert!(!csrf_exempt(&Auth::Service, &auth, None));
+        assert!(!csrf_exempt(
+            &Auth::Anonymous { accounts: true },
+            &auth,
+            Some("bogus")
+        ));
+    }
+
+    #[test]
+    fn test_web_auth() {
+        let auth: WebAuth = serde_json::from_str(
+            r#"{"username": "admin", "password": "hunter2", "tokens": ["abc", "def"]}"#,
+        )
+        .unwrap();
+        assert!(auth.enabled());
+        assert!(auth.accepts_basic("admin", "hunter2"));
+        assert!(!auth.accepts_basic("admin", "hunter"));
+        assert!(!auth.accepts_basic("admin2", "hunter2"));
+        assert!(auth.accepts_token("def"));
+        assert!(!auth.accepts_token("de"));
+        assert!(!auth.accepts_token(""));
+
+        let auth = WebAuth::default();
+        assert!(!auth.enabled());
+        assert!(!auth.accepts_basic("", ""));
+        assert!(!auth.accepts_token(""));
+    }
+}
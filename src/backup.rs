@@ -1,34 +1,106 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
+use log::{info, warn};
+use rusqlite::{params, OptionalExtension};
 
 use crate::common::{Service, VideoStatus};
 use crate::config::Config;
-use crate::db::{Channel, DBVideoInfo, Database};
-use crate::youtube::VideoInfo;
+use crate::db::{Channel, Database};
+use crate::sidecar::SidecarKind;
+
+/// Format version written by `export`. Files without a version are from before versioning was
+/// added, and are read as version 1
+const BACKUP_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Backup {
+    version: u32,
+    channels: Vec<BackupChannel>,
+    #[serde(default)]
+    users: Vec<BackupUser>,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct BackupChannel {
-    chanid: String,
     service: String,
-    icon: String,
-    id: i64,
+    chanid: String,
+    title: String,
+    thumbnail: String,
+    /// When the channel was last checked for new videos, in RFC 3339 format
+    last_update: Option<String>,
+    profile: Option<String>,
+    #[serde(default)]
+    videos: Vec<BackupVideo>,
 }
 
-impl From<&Channel> for BackupChannel {
-    fn from(src: &Channel) -> Self {
-        Self {
-            chanid: src.chanid.clone(),
-            service: src.service.as_str().into(),
-            icon: src.thumbnail.clone(),
-            id: src.id,
-        }
-    }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct BackupVideo {
+    video_id: String,
+    /// Unique, so used to refer to the video elsewhere in the backup
+    url: String,
+    title: String,
+    description: String,
+    thumbnail: String,
+    /// RFC 3339 format
+    published_at: String,
+    status: String,
+    file_path: Option<String>,
+    attempts: i64,
+    last_error: Option<String>,
+    profile: Option<String>,
+    watched: bool,
+    watch_position: f64,
+    #[serde(default)]
+    sidecars: Vec<BackupSidecar>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct BackupSidecar {
+    kind: String,
+    path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct BackupUser {
+    name: String,
+    /// Only exported when asked for, as the hash allows guessing the password offline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+    admin: bool,
+    #[serde(default)]
+    subscriptions: Vec<BackupChannelRef>,
+    #[serde(default)]
+    videos: Vec<BackupUserVideo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+struct BackupChannelRef {
+    service: String,
+    chanid: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct BackupVideoInfo {
+struct BackupUserVideo {
+    url: String,
+    watched: bool,
+    watch_position: f64,
+    ignored: bool,
+}
+
+/// Channel in a version 1 backup, which lacked the title
+#[derive(Deserialize, Debug, Clone)]
+struct LegacyChannel {
+    chanid: String,
+    service: String,
+    icon: String,
+    id: i64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct LegacyVideo {
     status: String,
     title: String,
     url: String,
@@ -37,92 +109,458 @@ struct BackupVideoInfo {
     description: String,
     thumbnail_url: String,
     channel_id: i64,
+    #[serde(default)]
+    watched: bool,
+    #[serde(default)]
+    watch_position: f64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Backup {
-    channels: Vec<BackupChannel>,
-    videos: Vec<BackupVideoInfo>,
-}
-
-impl From<BackupVideoInfo> for VideoInfo {
-    fn from(src: BackupVideoInfo) -> Self {
-        let when: DateTime<Utc> = DateTime::parse_from_rfc3339(&src.publishdate)
-            .expect("Invalid date")
-            .with_timezone(&Utc);
-
-        Self {
-            id: src.videoid,
-            url: src.url,
-            title: src.title,
-            description: src.description,
-            thumbnail_url: src.thumbnail_url,
-            published_at: when,
+#[derive(Deserialize, Debug, Clone)]
+struct LegacyBackup {
+    channels: Vec<LegacyChannel>,
+    videos: Vec<LegacyVideo>,
+}
+
+impl LegacyBackup {
+    /// Convert to the current format, with videos placed in their channels
+    fn upgrade(self) -> Result<Backup> {
+        let mut videos: HashMap<i64, Vec<BackupVideo>> = HashMap::new();
+        let known: HashSet<i64> = self.channels.iter().map(|c| c.id).collect();
+        for v in self.videos {
+            if !known.contains(&v.channel_id) {
+                return Err(anyhow::anyhow!(
+                    "Video {:?} refers to unknown channel {}",
+                    v.url,
+                    v.channel_id
+                ));
+            }
+            videos.entry(v.channel_id).or_default().push(BackupVideo {
+                video_id: v.videoid,
+                url: v.url,
+                title: v.title,
+                description: v.description,
+                thumbnail: v.thumbnail_url,
+                published_at: v.publishdate,
+                status: v.status,
+                file_path: None,
+                attempts: 0,
+                last_error: None,
+                profile: None,
+                watched: v.watched,
+                watch_position: v.watch_position,
+                sidecars: vec![],
+            });
         }
+
+        let channels = self
+            .channels
+            .into_iter()
+            .map(|c| BackupChannel {
+                videos: videos.remove(&c.id).unwrap_or_default(),
+                service: c.service,
+                // Title was not stored, so use the ID until the next update fetches it
+                title: c.chanid.clone(),
+                chanid: c.chanid,
+                thumbnail: c.icon,
+                last_update: None,
+                profile: None,
+            })
+            .collect();
+        Ok(Backup {
+            version: BACKUP_VERSION,
+            channels,
+            users: vec![],
+        })
     }
 }
 
-impl From<&DBVideoInfo> for BackupVideoInfo {
-    fn from(src: &DBVideoInfo) -> Self {
-        Self {
-            channel_id: src.chanid,
-            status: src.status.as_str().into(),
-            title: src.info.title.clone(),
-            url: src.info.url.clone(),
-            videoid: src.info.id.clone(),
-            publishdate: src.info.published_at.to_rfc3339(),
-            description: src.info.description.clone(),
-            thumbnail_url: src.info.thumbnail_url.clone(),
+fn parse_date(value: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(value)
+        .with_context(|| format!("Invalid date {:?}", value))?
+        .with_timezone(&Utc))
+}
+
+impl Backup {
+    /// Read a backup of any supported version
+    fn parse(data: &str) -> Result<Backup> {
+        let value: serde_json::Value =
+            serde_json::from_str(data).context("Backup is not valid JSON")?;
+        let back = match value.get("version").map(|v| v.as_u64()) {
+            None => serde_json::from_value::<LegacyBackup>(value)
+                .context("Invalid version 1 backup")?
+                .upgrade()?,
+            Some(Some(v)) if v == BACKUP_VERSION as u64 => {
+                serde_json::from_value(value).context("Invalid backup")?
+            }
+            Some(Some(v)) if v > BACKUP_VERSION as u64 => {
+                return Err(anyhow::anyhow!(
+                    "Backup version {} is newer than this version of vidl supports ({})",
+                    v,
+                    BACKUP_VERSION
+                ))
+            }
+            Some(v) => return Err(anyhow::anyhow!("Unknown backup version {:?}", v)),
+        };
+        back.validate()?;
+        Ok(back)
+    }
+
+    /// Check every value can be stored, so a bad entry is found before anything is changed
+    fn validate(&self) -> Result<()> {
+        let mut errors: Vec<String> = vec![];
+        let mut check = |what: &dyn Fn() -> String, result: Result<()>| {
+            if let Err(e) = result {
+                errors.push(format!("{} - {:#}", what(), e));
+            }
+        };
+
+        let mut channels = HashSet::new();
+        let mut urls = HashSet::new();
+        for c in &self.channels {
+            let what = || format!("Channel {:?}", c.chanid);
+            check(&what, Service::from_str(&c.service).map(|_| ()));
+            if let Some(d) = &c.last_update {
+                check(&what, parse_date(d).map(|_| ()));
+            }
+            if !channels.insert((&c.service, &c.chanid)) {
+                check(&what, Err(anyhow::anyhow!("Duplicate channel")));
+            }
+
+            for v in &c.videos {
+                let what = || format!("Video {:?}", v.url);
+                check(&what, VideoStatus::from_str(&v.status).map(|_| ()));
+                check(&what, parse_date(&v.published_at).map(|_| ()));
+                for s in &v.sidecars {
+                    check(&what, SidecarKind::from_str(&s.kind).map(|_| ()));
+                }
+                if !urls.insert(&v.url) {
+                    check(&what, Err(anyhow::anyhow!("Duplicate video URL")));
+                }
+            }
+        }
+
+        for u in &self.users {
+            let what = || format!("User {:?}", u.name);
+            for s in &u.subscriptions {
+                if !channels.contains(&(&s.service, &s.chanid)) {
+                    check(
+                        &what,
+                        Err(anyhow::anyhow!(
+                            "Subscribed to unknown channel {:?}",
+                            s.chanid
+                        )),
+                    );
+                }
+            }
+            for v in &u.videos {
+                if !urls.contains(&v.url) {
+                    check(
+                        &what,
+                        Err(anyhow::anyhow!("State for unknown video {:?}", v.url)),
+                    );
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Invalid backup:\n  {}",
+                errors.join("\n  ")
+            ))
         }
     }
-}
 
-/// Load backup file
-pub fn import() -> Result<()> {
-    let cfg = Config::load();
-    let db = Database::open(&cfg)?;
+    /// Read everything from the database, ordered so the same contents always give the same
+    /// backup. Users' password hashes are only included with `include_credentials`
+    fn read(db: &Database, include_credentials: bool) -> Result<Backup> {
+        let mut chans = crate::db::list_channels(db)?;
+        chans.sort_by_key(|c| c.id);
 
-    let stdin = std::io::stdin();
-    let lock = stdin.lock();
-    let back: Backup = serde_json::from_reader(lock)?;
+        let mut vids = crate::db::all_videos(db, &Default::default(), i64::MAX, 0)?;
+        vids.sort_by_key(|v| v.id);
+        let mut videos: HashMap<i64, Vec<BackupVideo>> = HashMap::new();
+        for v in vids {
+            let sidecars = v
+                .sidecars(db)?
+                .into_iter()
+                .map(|(kind, path)| BackupSidecar {
+                    kind: kind.as_str().into(),
+                    path,
+                })
+                .collect();
+            videos.entry(v.chanid).or_default().push(BackupVideo {
+                video_id: v.info.id,
+                url: v.info.url,
+                title: v.info.title,
+                description: v.info.description,
+                thumbnail: v.info.thumbnail_url,
+                published_at: v.info.published_at.to_rfc3339(),
+                status: v.status.as_str().into(),
+                file_path: v.file_path,
+                attempts: v.attempts,
+                last_error: v.last_error,
+                profile: v.profile,
+                watched: v.watched,
+                watch_position: v.watch_position,
+                sidecars,
+            });
+        }
 
-    let mut chanmap: HashMap<i64, Channel> = HashMap::new();
-    for chan in back.channels {
-        let service = Service::from_str(&chan.service)?;
-        let cid = service.get_channel_id(&chan.chanid);
-        let db_chan = crate::db::Channel::get(&db, &cid)
-            .or_else(|_| crate::db::Channel::create(&db, &cid, &chan.chanid, &chan.icon))?;
-        chanmap.insert(db_chan.id, db_chan);
+        let mut channels = vec![];
+        for c in chans {
+            channels.push(BackupChannel {
+                service: c.service.as_str().into(),
+                last_update: c.last_update(db)?.map(|d| d.to_rfc3339()),
+                videos: videos.remove(&c.id).unwrap_or_default(),
+                chanid: c.chanid,
+                title: c.title,
+                thumbnail: c.thumbnail,
+                profile: c.profile,
+            });
+        }
+
+        Ok(Backup {
+            version: BACKUP_VERSION,
+            channels,
+            users: read_users(db, include_credentials)?,
+        })
     }
 
-    for backup_vid in back.videos {
-        let db_chan = &chanmap[&backup_vid.channel_id];
+    /// Add everything to the database, replacing the state of any channels, videos and users
+    /// which already exist. Nothing is changed if any part fails
+    fn restore(&self, db: &Database) -> Result<()> {
+        db.conn.execute_batch("BEGIN")?;
+        match self.restore_all(db) {
+            Ok(()) => {
+                db.conn.execute_batch("COMMIT")?;
+                Ok(())
+            }
+            Err(e) => {
+                db.conn.execute_batch("ROLLBACK")?;
+                Err(e)
+            }
+        }
+    }
+
+    fn restore_all(&self, db: &Database) -> Result<()> {
+        let mut channel_ids: HashMap<BackupChannelRef, i64> = HashMap::new();
+        for c in &self.channels {
+            let cid = Service::from_str(&c.service)?.get_channel_id(&c.chanid);
+            let chan = Channel::get(db, &cid)
+                .or_else(|_| Channel::create(db, &cid, &c.title, &c.thumbnail))?;
+            let last_update = c.last_update.as_deref().map(parse_date).transpose()?;
+            db.conn
+                .execute(
+                    "UPDATE channel SET title=?1, thumbnail=?2, last_update=?3, profile=?4
+                        WHERE id=?5",
+                    params![c.title, c.thumbnail, last_update, c.profile, chan.id],
+                )
+                .with_context(|| format!("Failed to restore channel {:?}", c.chanid))?;
+            channel_ids.insert(
+                BackupChannelRef {
+                    service: c.service.clone(),
+                    chanid: c.chanid.clone(),
+                },
+                chan.id,
+            );
+
+            for v in &c.videos {
+                restore_video(db, chan.id, v)
+                    .with_context(|| format!("Failed to restore video {:?}", v.url))?;
+            }
+        }
+
+        for u in &self.users {
+            restore_user(db, u, &channel_ids)
+                .with_context(|| format!("Failed to restore user {:?}", u.name))?;
+        }
+        Ok(())
+    }
+}
 
-        let status = VideoStatus::from_str(&backup_vid.status)?;
-        let v: VideoInfo = backup_vid.into();
-        match db_chan.add_video(&db, &v) {
-            Ok(dbv) => dbv.set_status(&db, status)?,
-            Err(e) => eprintln!("{:?}", e),
+fn restore_video(db: &Database, channel: i64, v: &BackupVideo) -> Result<()> {
+    let existing: Option<i64> = db
+        .conn
+        .query_row("SELECT id FROM video WHERE url=?1", params![v.url], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    let id = match existing {
+        Some(id) => id,
+        None => {
+            db.conn.execute(
+                "INSERT INTO video (channel, video_id, url, title, description, thumbnail,
+                    published_at, status)
+                    VALUES (?1, ?2, ?3, '', '', '', '', '')",
+                params![channel, v.video_id, v.url],
+            )?;
+            db.conn.last_insert_rowid()
         }
+    };
+
+    // Dates are stored the same way as by `Channel::add_video`
+    let published_at = parse_date(&v.published_at)?.to_rfc3339();
+    db.conn.execute(
+        "UPDATE video SET channel=?1, video_id=?2, title=?3, description=?4, thumbnail=?5,
+            published_at=?6, status=?7, file_path=?8, attempts=?9, last_error=?10, profile=?11,
+            watched=?12, watch_position=?13
+            WHERE id=?14",
+        params![
+            channel,
+            v.video_id,
+            v.title,
+            v.description,
+            v.thumbnail,
+            published_at,
+            v.status,
+            v.file_path,
+            v.attempts,
+            v.last_error,
+            v.profile,
+            v.watched,
+            v.watch_position,
+            id
+        ],
+    )?;
+
+    db.conn
+        .execute("DELETE FROM sidecar WHERE video=?1", params![id])?;
+    for s in &v.sidecars {
+        db.conn.execute(
+            "INSERT OR REPLACE INTO sidecar (video, kind, path) VALUES (?1, ?2, ?3)",
+            params![id, s.kind, s.path],
+        )?;
     }
     Ok(())
 }
 
-/// Export channels, videos, and their status etc to a JSON file
-pub fn export(output: Option<&str>) -> Result<()> {
-    let cfg = Config::load();
+fn read_users(db: &Database, include_credentials: bool) -> Result<Vec<BackupUser>> {
+    let mut q = db
+        .conn
+        .prepare("SELECT id, name, password_hash, admin FROM user ORDER BY id")?;
+    let rows = q.query_map(params![], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+
+    let mut users = vec![];
+    for row in rows {
+        let (id, name, password_hash, admin): (i64, String, String, bool) = row?;
+
+        let mut q = db.conn.prepare(
+            "SELECT c.service, c.chanid FROM subscription s
+                JOIN channel c ON c.id = s.channel
+                WHERE s.user=?1 ORDER BY c.id",
+        )?;
+        let subscriptions = q
+            .query_map(params![id], |row| {
+                Ok(BackupChannelRef {
+                    service: row.get(0)?,
+                    chanid: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut q = db.conn.prepare(
+            "SELECT v.url, uv.watched, uv.watch_position, uv.ignored FROM user_video uv
+                JOIN video v ON v.id = uv.video
+                WHERE uv.user=?1 ORDER BY v.id",
+        )?;
+        let videos = q
+            .query_map(params![id], |row| {
+                Ok(BackupUserVideo {
+                    url: row.get(0)?,
+                    watched: row.get(1)?,
+                    watch_position: row.get(2)?,
+                    ignored: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        users.push(BackupUser {
+            name,
+            password_hash: Some(password_hash).filter(|_| include_credentials),
+            admin,
+            subscriptions,
+            videos,
+        });
+    }
+    Ok(users)
+}
+
+fn restore_user(
+    db: &Database,
+    u: &BackupUser,
+    channel_ids: &HashMap<BackupChannelRef, i64>,
+) -> Result<()> {
+    // New users without a password hash cannot log in until one is set, and existing users
+    // keep their current password
+    db.conn.execute(
+        "INSERT OR IGNORE INTO user (name, password_hash, admin) VALUES (?1, '', ?2)",
+        params![u.name, u.admin],
+    )?;
+    db.conn.execute(
+        "UPDATE user SET password_hash=COALESCE(?1, password_hash), admin=?2 WHERE name=?3",
+        params![u.password_hash, u.admin, u.name],
+    )?;
+    let id: i64 = db.conn.query_row(
+        "SELECT id FROM user WHERE name=?1",
+        params![u.name],
+        |row| row.get(0),
+    )?;
+
+    for s in &u.subscriptions {
+        let channel = channel_ids
+            .get(s)
+            .ok_or_else(|| anyhow::anyhow!("Subscribed to unknown channel {:?}", s.chanid))?;
+        db.conn.execute(
+            "INSERT OR IGNORE INTO subscription (user, channel) VALUES (?1, ?2)",
+            params![id, channel],
+        )?;
+    }
+    for v in &u.videos {
+        db.conn.execute(
+            "INSERT OR REPLACE INTO user_video (user, video, watched, watch_position, ignored)
+                SELECT ?1, id, ?2, ?3, ?4 FROM video WHERE url=?5",
+            params![id, v.watched, v.watch_position, v.ignored, v.url],
+        )?;
+    }
+    Ok(())
+}
+
+/// Load backup file from stdin. The whole file is checked before the database is changed
+pub fn import() -> Result<()> {
+    let cfg = Config::load()?;
     let db = Database::open(&cfg)?;
 
-    let chans = crate::db::list_channels(&db)?;
-    let chans_ser: Vec<BackupChannel> = chans.iter().map(|v| v.into()).collect();
+    let mut data = String::new();
+    std::io::stdin().read_to_string(&mut data)?;
+    let back = Backup::parse(&data)?;
+    back.restore(&db)?;
+    info!(
+        "Restored {} channels, {} videos and {} users",
+        back.channels.len(),
+        back.channels.iter().map(|c| c.videos.len()).sum::<usize>(),
+        back.users.len()
+    );
+    let without = back.users.iter().filter(|u| u.password_hash.is_none());
+    for u in without {
+        warn!(
+            "Backup has no password for {:?}, if they are new set one with `vidl user passwd`",
+            u.name
+        );
+    }
+    Ok(())
+}
 
-    let vids = crate::db::all_videos(&db, std::i64::MAX, 0)?;
-    let vids_ser: Vec<BackupVideoInfo> = vids.iter().map(|v| v.into()).collect();
+/// Export channels, videos, users and their state to a JSON file. Password hashes are only
+/// written with `include_credentials`
+pub fn export(output: Option<&str>, include_credentials: bool) -> Result<()> {
+    let cfg = Config::load()?;
+    let db = Database::open(&cfg)?;
 
-    let back = Backup {
-        channels: chans_ser,
-        videos: vids_ser,
-    };
+    let back = Backup::read(&db, include_credentials)?;
 
     let stdout = std::io::stdout();
     if let Some(output) = output {
@@ -134,3 +572,140 @@ pub fn export(output: Option<&str>) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::users::User;
+    use crate::youtube::VideoInfo;
+
+    fn video(id: &str, when: &str) -> VideoInfo {
+        VideoInfo {
+            id: id.into(),
+            url: format!("https://www.youtube.com/watch?v={}", id),
+            title: format!("Video {}", id),
+            description: "Some \"description\"".into(),
+            thumbnail_url: "https://example.com/thumb.jpg".into(),
+            published_at: parse_date(when).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let db = Database::open_in_memory()?;
+        let a = Channel::create(&db, &Service::Youtube.get_channel_id("UCa"), "A", "a.jpg")?;
+        let b = Channel::create(&db, &Service::Youtube.get_channel_id("UCb"), "B", "b.jpg")?;
+        a.set_last_update(&db)?;
+        b.set_profile(&db, Some("audio"))?;
+
+        // Interleave videos between channels, so the IDs differ after restoring
+        let v1 = a.add_video(&db, &video("1", "2020-01-02T03:04:05+00:00"))?;
+        let v2 = b.add_video(&db, &video("2", "2020-02-02T03:04:05.5+00:00"))?;
+        a.add_video(&db, &video("3", "2020-03-02T03:04:05+00:00"))?;
+        v1.set_status(&db, VideoStatus::Grabbed)?;
+        v1.set_file_path(&db, Some("/videos/1.mp4"))?;
+        v1.add_sidecar(&db, SidecarKind::Subtitle, "/videos/1.en.vtt")?;
+        v1.record_attempt(&db, None)?;
+        v2.record_attempt(&db, Some("HTTP Error 403"))?;
+        v2.set_profile(&db, Some("720p"))?;
+        v2.set_watch_position(&db, 12.5)?;
+
+        let u = User::create(&db, "someone", "hunter2", true)?;
+        u.subscribe(&db, &b)?;
+        u.set_watched(&db, &v2, true)?;
+
+        let exported = serde_json::to_string_pretty(&Backup::read(&db, true)?)?;
+
+        let restored = Database::open_in_memory()?;
+        Backup::parse(&exported)?.restore(&restored)?;
+        let reexported = serde_json::to_string_pretty(&Backup::read(&restored, true)?)?;
+        assert_eq!(exported, reexported);
+        assert!(User::authenticate(&restored, "someone", "hunter2")?.is_some());
+
+        // Restoring over existing data changes nothing
+        Backup::parse(&exported)?.restore(&restored)?;
+        assert_eq!(
+            exported,
+            serde_json::to_string_pretty(&Backup::read(&restored, true)?)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_credentials() -> Result<()> {
+        let db = Database::open_in_memory()?;
+        User::create(&db, "someone", "hunter2", true)?;
+        let exported = serde_json::to_string(&Backup::read(&db, false)?)?;
+        assert!(!exported.contains("password_hash"), "{}", exported);
+
+        // New users cannot log in until a password is set
+        let restored = Database::open_in_memory()?;
+        Backup::parse(&exported)?.restore(&restored)?;
+        let u = User::get_by_name(&restored, "someone")?;
+        assert!(u.admin);
+        assert!(User::authenticate(&restored, "someone", "")?.is_none());
+        u.set_password(&restored, "secret")?;
+
+        // ..and existing users keep their password
+        Backup::parse(&exported)?.restore(&restored)?;
+        assert!(User::authenticate(&restored, "someone", "secret")?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy() -> Result<()> {
+        let data = r#"{
+            "channels": [{"chanid": "UCa", "service": "youtube", "icon": "a.jpg", "id": 7}],
+            "videos": [{"status": "GR", "title": "One", "url": "https://example.com/1",
+                "videoid": "1", "publishdate": "2020-01-02T03:04:05+00:00",
+                "description": "", "thumbnail_url": "", "channel_id": 7, "watched": true}]
+        }"#;
+        let db = Database::open_in_memory()?;
+        Backup::parse(data)?.restore(&db)?;
+        let back = Backup::read(&db, true)?;
+        assert_eq!(back.channels.len(), 1);
+        assert_eq!(back.channels[0].title, "UCa");
+        assert_eq!(back.channels[0].thumbnail, "a.jpg");
+        assert_eq!(back.channels[0].videos.len(), 1);
+        assert_eq!(back.channels[0].videos[0].status, "GR");
+        assert!(back.channels[0].videos[0].watched);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() -> Result<()> {
+        let db = Database::open_in_memory()?;
+        let chan = Channel::create(&db, &Service::Youtube.get_channel_id("UCa"), "A", "")?;
+        chan.add_video(&db, &video("1", "2020-01-02T03:04:05+00:00"))?;
+        let mut back = Backup::read(&db, true)?;
+        back.channels[0].videos[0].published_at = "yesterday".into();
+        back.channels[0].videos[0].status = "??".into();
+        let data = serde_json::to_string(&back)?;
+
+        let err = format!("{:#}", Backup::parse(&data).unwrap_err());
+        assert!(err.contains("Invalid date \"yesterday\""), "{}", err);
+        assert!(err.contains("??"), "{}", err);
+
+        let newer = data.replace("\"version\":2", "\"version\":3");
+        assert!(Backup::parse(&newer).is_err());
+
+        // Failure part way through leaves the database untouched
+        let restored = Database::open_in_memory()?;
+        back.channels[0].videos[0].published_at = "2020-01-02T03:04:05+00:00".into();
+        back.channels[0].videos[0].status = "GR".into();
+        back.users.push(BackupUser {
+            name: "someone".into(),
+            password_hash: None,
+            admin: false,
+            subscriptions: vec![BackupChannelRef {
+                service: "youtube".into(),
+                chanid: "UCb".into(),
+            }],
+            videos: vec![],
+        });
+        assert!(back.validate().is_err());
+        assert!(back.restore(&restored).is_err());
+        assert!(crate::db::list_channels(&restored)?.is_empty());
+        Ok(())
+    }
+}
@@ -1,5 +1,14 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
 use anyhow::Result;
 
+/// Lock ignoring poisoning, as a thread panicking while holding a lock must not take down
+/// every other thread using it
+pub fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 /// Supported services
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Service {
@@ -68,7 +77,7 @@ impl ChannelID {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum VideoStatus {
     /// New video
     New,
@@ -113,3 +122,22 @@ impl VideoStatus {
         }
     }
 }
+
+/// Guess the MIME type of a downloaded file from its extension
+pub fn mime_for_path(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    match ext.as_deref() {
+        Some("mp4") | Some("m4v") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mkv") => "video/x-matroska",
+        Some("flv") => "video/x-flv",
+        Some("m4a") => "audio/mp4",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") | Some("opus") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
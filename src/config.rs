@@ -1,41 +1,426 @@
+use anyhow::Context;
 use directories::ProjectDirs;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::auth::WebAuth;
+use crate::download::DownloaderKind;
+use crate::hooks::Hook;
+use crate::layout::Layout;
+use crate::notify::Notifier;
+
+/// Named set of options controlling the format videos are downloaded in
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct QualityProfile {
+    /// Highest video resolution to download, e.g `1080`
+    pub max_height: Option<u32>,
+    /// Container format video and audio are merged into, e.g `"mkv"` or `"mp4"`
+    pub container: Option<String>,
+    /// Download only the audio
+    #[serde(default)]
+    pub audio_only: bool,
+    /// Codec audio-only downloads are converted to, e.g `"mp3"` or `"opus"`
+    pub audio_codec: Option<String>,
+    /// Languages of subtitles to download, e.g `["en", "de"]`
+    #[serde(default)]
+    pub subtitles: Vec<String>,
+    /// Download full size thumbnail alongside the video
+    #[serde(default)]
+    pub thumbnail: bool,
+    /// Write JSON file describing the video alongside it
+    #[serde(default)]
+    pub metadata: bool,
+}
+
+impl QualityProfile {
+    /// Arguments to pass to the downloader to achieve this profile
+    pub fn downloader_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec![];
+        if self.audio_only {
+            args.push("--format".into());
+            args.push("bestaudio/best".into());
+            args.push("--extract-audio".into());
+            if let Some(ref codec) = self.audio_codec {
+                args.push("--audio-format".into());
+                args.push(codec.clone());
+            }
+        } else {
+            if let Some(height) = self.max_height {
+                args.push("--format".into());
+                args.push(format!(
+                    "bestvideo[height<={h}]+bestaudio/best[height<={h}]",
+                    h = height
+                ));
+            }
+            if let Some(ref container) = self.container {
+                args.push("--merge-output-format".into());
+                args.push(container.clone());
+            }
+        }
+        if !self.subtitles.is_empty() {
+            args.push("--write-sub".into());
+            args.push("--sub-lang".into());
+            args.push(self.subtitles.join(","));
+        }
+        if self.thumbnail {
+            args.push("--write-thumbnail".into());
+        }
+        args
+    }
+}
+
+/// Profiles always available, which can be replaced by ones of the same name in the config file
+fn builtin_profiles() -> BTreeMap<String, QualityProfile> {
+    let mut profiles = BTreeMap::new();
+    profiles.insert("best".into(), QualityProfile::default());
+    for height in &[1080, 720, 480] {
+        profiles.insert(
+            format!("{}p", height),
+            QualityProfile {
+                max_height: Some(*height),
+                ..Default::default()
+            },
+        );
+    }
+    profiles.insert(
+        "archive".into(),
+        QualityProfile {
+            subtitles: vec!["en".into()],
+            thumbnail: true,
+            metadata: true,
+            ..Default::default()
+        },
+    );
+    profiles.insert(
+        "audio".into(),
+        QualityProfile {
+            audio_only: true,
+            audio_codec: Some("mp3".into()),
+            ..Default::default()
+        },
+    );
+    profiles
+}
+
+/// Settings which can be changed from the web interface. Anything else could be used to run
+/// commands on the server (hooks and the downloader) or to see its credentials, so can only be
+/// changed by editing `config.json`
+const WEB_EDITABLE: &[&str] = &[
+    "download_retries",
+    "download_retry_delay_secs",
+    "profiles",
+    "default_profile",
+    "layout",
+    "notifiers",
+    "thumbnail_cache_mb",
+    "min_free_space_mb",
+];
+
+/// Top level of a `config.json`, which is empty if the file does not exist
+fn config_object(data: &str) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    if data.trim().is_empty() {
+        return Ok(Default::default());
+    }
+    match serde_json::from_str(data)? {
+        serde_json::Value::Object(settings) => Ok(settings),
+        _ => Err(anyhow::anyhow!("Config must be a JSON object")),
+    }
+}
+
+/// Optional settings read from `config.json` in the config directory, overriding the defaults
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    download_retries: Option<u32>,
+    download_retry_delay_secs: Option<u64>,
+    downloader: Option<DownloaderKind>,
+    downloader_path: Option<PathBuf>,
+    #[serde(default)]
+    profiles: BTreeMap<String, QualityProfile>,
+    default_profile: Option<String>,
+    layout: Option<Layout>,
+    #[serde(default)]
+    hooks: Vec<Hook>,
+    #[serde(default)]
+    notifiers: Vec<Notifier>,
+    auth: Option<WebAuth>,
+    thumbnail_cache_mb: Option<u64>,
+    min_free_space_mb: Option<u64>,
+}
 
 pub struct Config {
     db_filepath: PathBuf,
+    config_filepath: PathBuf,
+    thumbnail_dir: PathBuf,
     pub web_host: String,
     pub web_port: String,
     pub extra_youtubedl_args: Vec<String>,
     pub download_dir: PathBuf,
     pub filename_format: String,
-    pub num_workers: usize,
+    /// How many times a download failing with a transient error is retried
+    pub download_retries: u32,
+    /// Delay before the first retry, doubling for each subsequent attempt
+    pub download_retry_delay: Duration,
+    /// Program used to download videos
+    pub downloader: DownloaderKind,
+    /// Location of the downloader binary, if not the default name found on `PATH`
+    pub downloader_path: Option<PathBuf>,
+    /// Available quality profiles by name
+    pub profiles: BTreeMap<String, QualityProfile>,
+    /// Profile used for channels which have not been assigned one
+    pub default_profile: String,
+    /// How files are arranged within `download_dir`
+    pub layout: Layout,
+    /// Commands run after each successful download
+    pub hooks: Vec<Hook>,
+    /// Where to send notifications about new videos and downloads
+    pub notifiers: Vec<Notifier>,
+    /// Credentials required by the web server
+    pub web_auth: WebAuth,
+    /// Maximum size of the thumbnail cache in bytes
+    pub thumbnail_cache_size: u64,
+    /// Free space required in `download_dir` for vidl to report itself ready, in bytes
+    pub min_free_space: u64,
 }
 
 impl Config {
-    pub fn load() -> Config {
+    /// Load settings, failing if `config.json` or the environment contains invalid values
+    pub fn load() -> anyhow::Result<Config> {
         let pd = ProjectDirs::from("uk.co", "dbrweb", "vidl")
             .expect("Unable to determine configuration directories");
         let cfg: PathBuf = PathBuf::from(pd.data_dir());
 
         let config_dir = std::env::var("VIDL_CONFIG_DIR")
-            .and_then(|p| Ok(PathBuf::from(p)))
+            .map(PathBuf::from)
             .unwrap_or(cfg);
+        let mut cfg = Config::load_from(&config_dir)?;
+
+        if let Ok(name) = std::env::var("VIDL_DOWNLOADER") {
+            cfg.downloader = DownloaderKind::from_str(&name).context("Invalid VIDL_DOWNLOADER")?;
+        }
+        if let Ok(path) = std::env::var("VIDL_DOWNLOADER_PATH") {
+            cfg.downloader_path = Some(PathBuf::from(path));
+        }
+        if let Ok(dir) = std::env::var("VIDL_DOWNLOAD_DIR") {
+            cfg.download_dir = PathBuf::from(dir);
+        }
+        Ok(cfg)
+    }
+
+    /// Load settings using the database and `config.json` in the given directory, ignoring the
+    /// environment
+    pub fn load_from(config_dir: &Path) -> anyhow::Result<Config> {
         let db_filepath = config_dir.join("vidl.sqlite3");
 
-        Config {
-            db_filepath: db_filepath,
+        let config_path = config_dir.join("config.json");
+        let file: ConfigFile = match std::fs::read_to_string(&config_path) {
+            Ok(data) => serde_json::from_str(&data)
+                .with_context(|| format!("Invalid config file {:?}", &config_path))?,
+            Err(_) => ConfigFile::default(),
+        };
+
+        let mut profiles = builtin_profiles();
+        profiles.extend(file.profiles);
+
+        Ok(Config {
+            db_filepath,
+            config_filepath: config_path,
+            thumbnail_dir: config_dir.join("thumbnails"),
             web_host: "0.0.0.0".into(),
             web_port: "8448".into(),
             extra_youtubedl_args: vec!["--restrict-filenames".into(), "--continue".into()],
-            download_dir: PathBuf::from(
-                std::env::var("VIDL_DOWNLOAD_DIR").unwrap_or("./download".into()),
-            ),
+            download_dir: PathBuf::from("./download"),
             filename_format: "%(uploader)s__%(upload_date)s_%(title)s__%(id)s.%(ext)s".into(),
-            num_workers: 4,
-        }
+            download_retries: file.download_retries.unwrap_or(3),
+            download_retry_delay: Duration::from_secs(file.download_retry_delay_secs.unwrap_or(30)),
+            downloader: file.downloader.unwrap_or(DownloaderKind::YoutubeDl),
+            downloader_path: file.downloader_path,
+            profiles,
+            default_profile: file.default_profile.unwrap_or_else(|| "best".into()),
+            layout: file.layout.unwrap_or(Layout::Flat),
+            hooks: file.hooks,
+            notifiers: file.notifiers,
+            web_auth: file.auth.unwrap_or_default(),
+            thumbnail_cache_size: file.thumbnail_cache_mb.unwrap_or(200) * 1024 * 1024,
+            min_free_space: file.min_free_space_mb.unwrap_or(1024) * 1024 * 1024,
+        })
     }
 
     pub fn db_filepath(&self) -> &PathBuf {
         &self.db_filepath
     }
+
+    /// Directory thumbnails are cached in
+    pub fn thumbnail_dir(&self) -> &PathBuf {
+        &self.thumbnail_dir
+    }
+
+    /// Location of the optional `config.json`
+    pub fn config_filepath(&self) -> &PathBuf {
+        &self.config_filepath
+    }
+
+    /// Check contents of a `config.json` can be loaded, so it can be edited without breaking
+    /// the next `Config::load`
+    pub fn validate_file(data: &str) -> anyhow::Result<()> {
+        let file: ConfigFile = serde_json::from_str(data)?;
+        if let Some(ref name) = file.default_profile {
+            if !file.profiles.contains_key(name) && !builtin_profiles().contains_key(name) {
+                return Err(anyhow::anyhow!("Unknown default_profile {:?}", name));
+            }
+        }
+        if let Some(ref auth) = file.auth {
+            if auth.username.is_some() != auth.password.is_some() {
+                return Err(anyhow::anyhow!(
+                    "auth needs both a username and password for basic authentication"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The settings from `config.json` which can be edited in the web interface
+    pub fn web_editable(data: &str) -> anyhow::Result<String> {
+        let mut settings = config_object(data)?;
+        settings.retain(|k, _| WEB_EDITABLE.contains(&k.as_str()));
+        Ok(serde_json::to_string_pretty(&settings)?)
+    }
+
+    /// Replace the web editable settings in `config.json` with `edited`, keeping everything
+    /// else as it was. Fails if `edited` contains any other setting
+    pub fn merge_web_edit(current: &str, edited: &str) -> anyhow::Result<String> {
+        let edited = config_object(edited)?;
+        if let Some(k) = edited.keys().find(|k| !WEB_EDITABLE.contains(&k.as_str())) {
+            return Err(anyhow::anyhow!(
+                "{} can only be changed by editing the file",
+                k
+            ));
+        }
+        let mut settings = config_object(current)?;
+        settings.retain(|k, _| !WEB_EDITABLE.contains(&k.as_str()));
+        settings.extend(edited);
+        let data = serde_json::to_string_pretty(&settings)?;
+        Config::validate_file(&data)?;
+        Ok(data)
+    }
+
+    /// Look up quality profile by name
+    pub fn profile(&self, name: &str) -> anyhow::Result<&QualityProfile> {
+        self.profiles
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown quality profile {:?}", name))
+    }
+
+    /// URL for connecting to the web server from this machine
+    pub fn local_web_url(&self) -> String {
+        let host = if self.web_host == "0.0.0.0" {
+            "127.0.0.1"
+        } else {
+            &self.web_host
+        };
+        format!("http://{}:{}", host, self.web_port)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CURRENT: &str = r#"{
+        "download_retries": 1,
+        "downloader": "yt-dlp",
+        "hooks": [{"command": ["true"]}],
+        "auth": {"username": "admin", "password": "hunter2", "tokens": ["abc"]}
+    }"#;
+
+    #[test]
+    fn test_web_editable() -> anyhow::Result<()> {
+        let shown = Config::web_editable(CURRENT)?;
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&shown)?,
+            serde_json::json!({"download_retries": 1})
+        );
+        assert!(!shown.contains("hunter2"));
+        assert_eq!(Config::web_editable("")?, "{}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_web_edit() -> anyhow::Result<()> {
+        // Other settings are kept, and editable ones replaced
+        let merged = Config::merge_web_edit(CURRENT, r#"{"layout": "media-server"}"#)?;
+        let mut expected: serde_json::Value = serde_json::from_str(CURRENT)?;
+        expected.as_object_mut().unwrap().remove("download_retries");
+        expected["layout"] = "media-server".into();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&merged)?,
+            expected
+        );
+
+        assert!(Config::merge_web_edit(CURRENT, r#"{"default_profile": "nope"}"#).is_err());
+        assert!(Config::merge_web_edit(CURRENT, "[]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_web_edit_hooks() {
+        let err = Config::merge_web_edit(CURRENT, r#"{"hooks": [{"command": ["rm"]}]}"#);
+        assert!(format!("{}", err.unwrap_err()).contains("hooks"));
+    }
+
+    #[test]
+    fn test_merge_web_edit_downloader() {
+        assert!(Config::merge_web_edit(CURRENT, r#"{"downloader": "youtube-dl"}"#).is_err());
+    }
+
+    #[test]
+    fn test_merge_web_edit_downloader_path() {
+        assert!(Config::merge_web_edit(CURRENT, r#"{"downloader_path": "/tmp/evil"}"#).is_err());
+    }
+
+    #[test]
+    fn test_merge_web_edit_auth() {
+        assert!(Config::merge_web_edit(CURRENT, r#"{"auth": {"tokens": ["mine"]}}"#).is_err());
+    }
+
+    #[test]
+    fn test_profile_args() {
+        let profiles = builtin_profiles();
+        assert!(profiles["best"].downloader_args().is_empty());
+        assert_eq!(
+            profiles["480p"].downloader_args(),
+            vec![
+                "--format",
+                "bestvideo[height<=480]+bestaudio/best[height<=480]"
+            ]
+        );
+        assert_eq!(
+            profiles["audio"].downloader_args(),
+            vec![
+                "--format",
+                "bestaudio/best",
+                "--extract-audio",
+                "--audio-format",
+                "mp3"
+            ]
+        );
+
+        let subs = QualityProfile {
+            container: Some("mkv".into()),
+            subtitles: vec!["en".into(), "de".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            subs.downloader_args(),
+            vec![
+                "--merge-output-format",
+                "mkv",
+                "--write-sub",
+                "--sub-lang",
+                "en,de"
+            ]
+        );
+    }
 }
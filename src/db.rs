@@ -1,25 +1,27 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
 use anyhow::{Context, Result};
 use log::{debug, error, info};
-use rusqlite::types::FromSql;
+use rusqlite::types::{FromSql, Value};
 use rusqlite::{params, Connection};
 use thiserror::Error;
 
 use crate::common::{ChannelID, Service, VideoStatus};
 use crate::config::Config;
+use crate::sidecar::SidecarKind;
 use crate::youtube::{ChannelMetadata, VideoInfo};
 
 #[derive(Error, Debug)]
 pub enum DatabaseError {
-    #[error("Misc database error")]
-    DatabaseError,
-
     #[error("Invalid service string in database {0}")]
     InvalidServiceInDB(String),
 
     #[error("Invalid status string in database {0}")]
     InvalidStatusInDB(String),
+
+    #[error("Invalid sidecar kind string in database {0}")]
+    InvalidSidecarKindInDB(String),
 }
 
 #[derive(Debug)]
@@ -29,31 +31,55 @@ pub struct DBVideoInfo {
     pub info: VideoInfo,
     pub status: VideoStatus,
     pub chanid: i64,
+    /// Location of the downloaded file, if it has been grabbed
+    pub file_path: Option<String>,
+    /// Number of times downloading the video has been attempted
+    pub attempts: i64,
+    /// Error message from the most recent failed download attempt
+    pub last_error: Option<String>,
+    /// Quality profile requested for this video's download, overriding the channel's profile
+    pub profile: Option<String>,
+    /// Whether the user has watched the video, independent of its download status
+    pub watched: bool,
+    /// Position in seconds playback was last stopped at
+    pub watch_position: f64,
 }
 
+/// Columns selected by queries which are turned into a `DBVideoInfo` by `DBVideoInfo::from_row`
+const VIDEO_COLUMNS: &str = "id, status, video_id, url, title, description, thumbnail,
+    published_at, channel, file_path, attempts, last_error, profile, watched, watch_position";
+
 impl DBVideoInfo {
+    /// Create from a row containing the `VIDEO_COLUMNS`
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<DBVideoInfo> {
+        Ok(DBVideoInfo {
+            id: row.get(0)?,
+            status: row.get(1)?,
+            info: VideoInfo {
+                id: row.get(2)?,
+                url: row.get(3)?,
+                title: row.get(4)?,
+                description: row.get(5)?,
+                thumbnail_url: row.get(6)?,
+                published_at: row.get(7)?,
+            },
+            chanid: row.get(8)?,
+            file_path: row.get(9)?,
+            attempts: row.get(10)?,
+            last_error: row.get(11)?,
+            profile: row.get(12)?,
+            watched: row.get(13)?,
+            watch_position: row.get(14)?,
+        })
+    }
+
     pub fn get_by_sqlid(db: &Database, id: i64) -> Result<DBVideoInfo> {
         let chan = db
             .conn
             .query_row(
-                "SELECT id, status, video_id, url, title, description, thumbnail, published_at, channel FROM video
-                WHERE id=?1",
+                &format!("SELECT {} FROM video WHERE id=?1", VIDEO_COLUMNS),
                 params![id],
-                |row| {
-                    Ok(DBVideoInfo {
-                        id: row.get(0)?,
-                        status: row.get(1)?,
-                        info: VideoInfo {
-                            id: row.get(2)?,
-                            url: row.get(3)?,
-                            title: row.get(4)?,
-                            description: row.get(5)?,
-                            thumbnail_url: row.get(6)?,
-                            published_at: row.get(7)?,
-                        },
-                        chanid: row.get(8)?,
-                    })
-                },
+                DBVideoInfo::from_row,
             )
             .context("Failed to find channel")?;
 
@@ -61,13 +87,12 @@ impl DBVideoInfo {
     }
 
     pub fn channel(&self, db: &Database) -> Result<Channel> {
-        let chan = Channel::get_by_sqlid(&db, self.chanid)?;
+        let chan = Channel::get_by_sqlid(db, self.chanid)?;
         Ok(chan)
     }
 
     pub fn set_status(&self, db: &Database, status: VideoStatus) -> Result<()> {
-        let chan = db
-            .conn
+        db.conn
             .execute(
                 "UPDATE video SET status=?1 WHERE id=?2",
                 params![status.as_str(), self.id],
@@ -76,8 +101,192 @@ impl DBVideoInfo {
 
         Ok(())
     }
+
+    /// Position of this video among its channel's videos published in the same year, starting
+    /// from 1. Videos published at the same time are ordered by when they were added
+    pub fn number_in_year(&self, db: &Database) -> Result<u32> {
+        let num = db
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM video v, video this
+                    WHERE this.id=?1 AND v.channel=this.channel
+                    AND strftime('%Y', v.published_at)=strftime('%Y', this.published_at)
+                    AND (v.published_at < this.published_at
+                        OR (v.published_at = this.published_at AND v.id <= this.id))",
+                params![self.id],
+                |row| row.get(0),
+            )
+            .context("Failed to number video")?;
+        Ok(num)
+    }
+
+    /// Record where the downloaded file for this video is stored (or `None` to clear it)
+    pub fn set_file_path(&self, db: &Database, path: Option<&str>) -> Result<()> {
+        db.conn
+            .execute(
+                "UPDATE video SET file_path=?1 WHERE id=?2",
+                params![path, self.id],
+            )
+            .context("Failed to update video file path")?;
+
+        Ok(())
+    }
+
+    /// Record an extra file (subtitles etc) stored alongside the downloaded file
+    pub fn add_sidecar(&self, db: &Database, kind: SidecarKind, path: &str) -> Result<()> {
+        db.conn
+            .execute(
+                "INSERT OR REPLACE INTO sidecar (video, kind, path) VALUES (?1, ?2, ?3)",
+                params![self.id, kind.as_str(), path],
+            )
+            .context("Failed to add sidecar file")?;
+
+        Ok(())
+    }
+
+    /// Extra files stored alongside the downloaded file
+    pub fn sidecars(&self, db: &Database) -> Result<Vec<(SidecarKind, String)>> {
+        let mut q = db
+            .conn
+            .prepare("SELECT kind, path FROM sidecar WHERE video=?1 ORDER BY path")?;
+        let mapped = q.query_map(params![self.id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut ret = vec![];
+        for r in mapped {
+            ret.push(r?);
+        }
+        Ok(ret)
+    }
+
+    /// Forget all sidecar files for this video
+    pub fn clear_sidecars(&self, db: &Database) -> Result<()> {
+        db.conn
+            .execute("DELETE FROM sidecar WHERE video=?1", params![self.id])
+            .context("Failed to clear sidecar files")?;
+
+        Ok(())
+    }
+
+    /// Set the quality profile to download this video with, or `None` to use the channel's
+    pub fn set_profile(&self, db: &Database, profile: Option<&str>) -> Result<()> {
+        db.conn
+            .execute(
+                "UPDATE video SET profile=?1 WHERE id=?2",
+                params![profile, self.id],
+            )
+            .context("Failed to update video profile")?;
+
+        Ok(())
+    }
+
+    /// Mark video as watched or unwatched. Marking it watched also resets the playback position,
+    /// so it is played from the start next time
+    pub fn set_watched(&self, db: &Database, watched: bool) -> Result<()> {
+        db.conn
+            .execute(
+                "UPDATE video SET watched=?1,
+                    watch_position=CASE WHEN ?1 THEN 0 ELSE watch_position END
+                    WHERE id=?2",
+                params![watched, self.id],
+            )
+            .context("Failed to update watched state")?;
+
+        Ok(())
+    }
+
+    /// Store the position in seconds playback was stopped at
+    pub fn set_watch_position(&self, db: &Database, position: f64) -> Result<()> {
+        db.conn
+            .execute(
+                "UPDATE video SET watch_position=?1 WHERE id=?2",
+                params![position, self.id],
+            )
+            .context("Failed to update watch position")?;
+
+        Ok(())
+    }
+
+    /// Increment the download attempt count, storing the error message from the attempt (or
+    /// clearing it if `None`, indicating success)
+    pub fn record_attempt(&self, db: &Database, error: Option<&str>) -> Result<()> {
+        db.conn
+            .execute(
+                "UPDATE video SET attempts=attempts+1, last_error=?1 WHERE id=?2",
+                params![error, self.id],
+            )
+            .context("Failed to record download attempt")?;
+
+        Ok(())
+    }
+
+    /// Record an error for the video without counting it as a download attempt
+    pub fn set_last_error(&self, db: &Database, error: Option<&str>) -> Result<()> {
+        db.conn
+            .execute(
+                "UPDATE video SET last_error=?1 WHERE id=?2",
+                params![error, self.id],
+            )
+            .context("Failed to set last error")?;
+
+        Ok(())
+    }
 }
 
+/// Changes to the schema made after the initial tables, applied in order by `Database::migrate`.
+/// Existing entries must never be modified, only appended to
+const MIGRATIONS: &[&str] = &[
+    // 1: Location of downloaded file
+    "ALTER TABLE video ADD COLUMN file_path TEXT NULL;",
+    // 2: Download attempt tracking
+    "ALTER TABLE video ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE video ADD COLUMN last_error TEXT NULL;",
+    // 3: Quality profiles
+    "ALTER TABLE channel ADD COLUMN profile TEXT NULL;
+    ALTER TABLE video ADD COLUMN profile TEXT NULL;",
+    // 4: Extra files downloaded alongside videos
+    "CREATE TABLE sidecar (
+        id            INTEGER PRIMARY KEY AUTOINCREMENT,
+        video         INTEGER NOT NULL,
+        kind          TEXT NOT NULL,
+        path          TEXT NOT NULL UNIQUE,
+        FOREIGN KEY(video) REFERENCES video(id)
+    );
+    CREATE INDEX idx_sidecar_video ON sidecar (video);",
+    // 5: Watched state, separate from download status
+    "ALTER TABLE video ADD COLUMN watched INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE video ADD COLUMN watch_position REAL NOT NULL DEFAULT 0;",
+    // 6: User accounts, with their subscriptions and per-video state
+    "CREATE TABLE user (
+        id            INTEGER PRIMARY KEY AUTOINCREMENT,
+        name          TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        admin         INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE session (
+        token         TEXT PRIMARY KEY,
+        user          INTEGER NOT NULL,
+        created_at    TEXT NOT NULL,
+        FOREIGN KEY(user) REFERENCES user(id)
+    );
+    CREATE TABLE subscription (
+        user          INTEGER NOT NULL,
+        channel       INTEGER NOT NULL,
+        PRIMARY KEY(user, channel),
+        FOREIGN KEY(user) REFERENCES user(id),
+        FOREIGN KEY(channel) REFERENCES channel(id)
+    );
+    CREATE TABLE user_video (
+        user          INTEGER NOT NULL,
+        video         INTEGER NOT NULL,
+        watched       INTEGER NOT NULL DEFAULT 0,
+        watch_position REAL NOT NULL DEFAULT 0,
+        ignored       INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY(user, video),
+        FOREIGN KEY(user) REFERENCES user(id),
+        FOREIGN KEY(video) REFERENCES video(id)
+    );",
+];
+
 /// Wraps connection to a database
 pub struct Database {
     pub conn: Connection,
@@ -123,8 +332,38 @@ impl Database {
         )
         .context("Creating video table")?;
 
+        Database::migrate(conn)?;
+
         Ok(())
     }
+
+    /// Apply any of the `MIGRATIONS` which have not yet been run on this database. The number
+    /// already applied is stored in SQLite's `user_version` pragma
+    fn migrate(conn: &Connection) -> Result<()> {
+        Database::apply_migrations(conn, MIGRATIONS)
+    }
+
+    /// Each migration is applied in a transaction along with the version bump, so one failing
+    /// part way through is rolled back entirely rather than left half applied
+    fn apply_migrations(conn: &Connection, migrations: &[&str]) -> Result<()> {
+        let version: i64 = conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        for (idx, migration) in migrations.iter().enumerate().skip(version as usize) {
+            debug!("Applying database migration {}", idx + 1);
+            let applied = conn
+                .execute_batch("BEGIN")
+                .and_then(|_| conn.execute_batch(migration))
+                .and_then(|_| conn.execute_batch(&format!("PRAGMA user_version = {}", idx + 1)))
+                .and_then(|_| conn.execute_batch("COMMIT"));
+            if let Err(e) = applied {
+                if !conn.is_autocommit() {
+                    conn.execute_batch("ROLLBACK")?;
+                }
+                return Err(e).with_context(|| format!("Applying database migration {}", idx + 1));
+            }
+        }
+        Ok(())
+    }
+
     /// Opens connection to database, creating tables as necessary
     pub fn open(cfg: &Config) -> Result<Database> {
         let path = cfg.db_filepath();
@@ -177,6 +416,19 @@ impl FromSql for VideoStatus {
     }
 }
 
+/// Converison from SQL text to `SidecarKind` instance
+impl FromSql for SidecarKind {
+    fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
+        let raw: &str = value.as_str()?;
+        match SidecarKind::from_str(raw) {
+            Ok(s) => Ok(s),
+            Err(_e) => Err(rusqlite::types::FromSqlError::Other(Box::new(
+                DatabaseError::InvalidSidecarKindInDB(raw.into()),
+            ))),
+        }
+    }
+}
+
 /// Channel which contains a bunch of videos
 #[derive(Debug)]
 pub struct Channel {
@@ -191,24 +443,33 @@ pub struct Channel {
     pub title: String,
     /// URL to icon for channel
     pub thumbnail: String,
+    /// Name of quality profile used for downloads, if not the default
+    pub profile: Option<String>,
 }
 
+/// Columns selected by queries which are turned into a `Channel` by `Channel::from_row`
+const CHANNEL_COLUMNS: &str = "id, chanid, service, title, thumbnail, profile";
+
 impl Channel {
+    /// Create from a row containing the `CHANNEL_COLUMNS`
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Channel> {
+        Ok(Channel {
+            id: row.get(0)?,
+            chanid: row.get(1)?,
+            service: row.get(2)?,
+            title: row.get(3)?,
+            thumbnail: row.get(4)?,
+            profile: row.get(5)?,
+        })
+    }
+
     pub fn get_by_sqlid(db: &Database, id: i64) -> Result<Channel> {
         let chan = db
             .conn
             .query_row(
-                "SELECT id, chanid, service, title, thumbnail FROM channel WHERE id=?1",
+                &format!("SELECT {} FROM channel WHERE id=?1", CHANNEL_COLUMNS),
                 params![id],
-                |row| {
-                    Ok(Channel {
-                        id: row.get(0)?,
-                        chanid: row.get(1)?,
-                        service: row.get(2)?,
-                        title: row.get(3)?,
-                        thumbnail: row.get(4)?,
-                    })
-                },
+                Channel::from_row,
             )
             .context("Failed to find channel")?;
 
@@ -217,19 +478,15 @@ impl Channel {
 
     /// Get Channel object for given channel, returning error it it does not exist
     pub fn get(db: &Database, cid: &ChannelID) -> Result<Channel> {
-        let chan = db.conn
+        let chan = db
+            .conn
             .query_row(
-                "SELECT id, chanid, service, title, thumbnail FROM channel WHERE chanid=?1 AND service = ?2",
+                &format!(
+                    "SELECT {} FROM channel WHERE chanid=?1 AND service = ?2",
+                    CHANNEL_COLUMNS
+                ),
                 params![cid.id_str(), cid.service().as_str()],
-                |row| {
-                    Ok(Channel {
-                        id: row.get(0)?,
-                        chanid: row.get(1)?,
-                        service: row.get(2)?,
-                        title: row.get(3)?,
-                        thumbnail: row.get(4)?,
-                    })
-                },
+                Channel::from_row,
             )
             .context("Failed to find channel")?;
 
@@ -272,14 +529,14 @@ impl Channel {
             .context("Insert channel query")?;
 
         // Return newly created channel
-        Channel::get(&db, cid)
+        Channel::get(db, cid)
     }
 
     pub fn last_update(&self, db: &Database) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
         let result: Option<chrono::DateTime<chrono::Utc>> = db.conn.query_row(
             "SELECT last_update FROM channel WHERE id=?1",
             params![self.id],
-            |row| Ok(row.get(0)?),
+            |row| row.get(0),
         )?;
         Ok(result)
     }
@@ -296,6 +553,17 @@ impl Channel {
         Ok(())
     }
 
+    /// Set the quality profile used for downloads from this channel, or `None` for the default
+    pub fn set_profile(&self, db: &Database, profile: Option<&str>) -> Result<()> {
+        db.conn
+            .execute(
+                "UPDATE channel SET profile=?1 WHERE id=?2",
+                params![profile, self.id],
+            )
+            .context("Failed to update channel profile")?;
+        Ok(())
+    }
+
     pub fn update_metadata(&self, db: &Database, meta: &ChannelMetadata) -> Result<()> {
         db.conn
             .execute(
@@ -324,7 +592,7 @@ impl Channel {
                 ],
             )
             .context("Add video query") {
-                Ok(k) => (Ok(k)),
+                Ok(k) => Ok(k),
                 Err(e) => {
                     println!("{:?}", e);
                     Err(e)
@@ -332,7 +600,31 @@ impl Channel {
             }?;
         let last_id = db.conn.last_insert_rowid();
 
-        Ok(DBVideoInfo::get_by_sqlid(&db, last_id)?)
+        DBVideoInfo::get_by_sqlid(db, last_id)
+    }
+
+    /// Mark new and failed videos in this channel published before `before` as ignored,
+    /// returning how many were changed
+    pub fn ignore_older_than(
+        &self,
+        db: &Database,
+        before: &chrono::DateTime<chrono::Utc>,
+    ) -> Result<usize> {
+        let changed = db
+            .conn
+            .execute(
+                "UPDATE video SET status=?1
+                    WHERE channel=?2 AND published_at < ?3 AND status IN (?4, ?5)",
+                params![
+                    VideoStatus::Ignore.as_str(),
+                    self.id,
+                    before.to_rfc3339(),
+                    VideoStatus::New.as_str(),
+                    VideoStatus::GrabError.as_str(),
+                ],
+            )
+            .context("Failed to ignore older videos")?;
+        Ok(changed)
     }
 
     /// Get the URL's of the most recently published videos - returning up to and including `num` results.
@@ -354,44 +646,20 @@ impl Channel {
         Ok(set)
     }
 
-    pub fn all_videos(&self, db: &Database, limit: i64, page: i64) -> Result<Vec<DBVideoInfo>> {
-        let mapper = |row: &rusqlite::Row| {
-            Ok(DBVideoInfo {
-                id: row.get(0)?,
-                status: row.get(1)?,
-                info: VideoInfo {
-                    id: row.get(2)?,
-                    url: row.get(3)?,
-                    title: row.get(4)?,
-                    description: row.get(5)?,
-                    thumbnail_url: row.get(6)?,
-                    published_at: row.get(7)?,
-                },
-                chanid: row.get(8)?,
-            })
-        };
-
-        let mut ret: Vec<DBVideoInfo> = vec![];
-
-        let mut q = db.conn.prepare(
-            "SELECT id, status, video_id, url, title, description, thumbnail, published_at, channel
-                FROM video
-                WHERE channel=?1
-                ORDER BY published_at DESC
-                LIMIT ?2
-                OFFSET ?3
-                ",
-        )?;
-        let mapped = q.query_map(params![self.id, limit, page * limit], mapper)?;
-        for r in mapped {
-            ret.push(r?);
-        }
-        Ok(ret)
+    pub fn all_videos(
+        &self,
+        db: &Database,
+        filter: &VideoFilter,
+        limit: i64,
+        page: i64,
+    ) -> Result<Vec<DBVideoInfo>> {
+        query_videos(db, Some(self.id), filter, limit, page)
     }
 
-    pub fn update(&self, db: &Database) -> Result<()> {
+    /// Check for new videos if not done recently, returning those which were added
+    pub fn update(&self, db: &Database) -> Result<Vec<DBVideoInfo>> {
         // Check if channel needs updating
-        let last_update = self.last_update(&db)?;
+        let last_update = self.last_update(db)?;
         let needs_update = if let Some(last_update) = last_update {
             let now = chrono::Utc::now();
             let delta = now - last_update;
@@ -405,40 +673,49 @@ impl Channel {
         // Skip if no updated required
         if !needs_update {
             info!("Channel updated recently, skipping {:?}", &self);
-            return Ok(());
+            return Ok(vec![]);
         }
 
         // Set updated time now (even in case of failure)
-        self.set_last_update(&db)?;
+        self.set_last_update(db)?;
 
         if self.service.as_str() != "youtube" {
             // FIXME
             error!("Ignoring Vimeo channel {:?}", &self);
-            return Ok(());
+            return Ok(vec![]);
         }
+
+        let started = std::time::Instant::now();
+        let added = self.fetch_new_videos(db);
+        let ok = matches!(added, Ok(Some(_)));
+        crate::metrics::update_checked(self.id, started.elapsed(), ok);
+        Ok(added?.unwrap_or_default())
+    }
+
+    /// Add videos published since the last update, returning `None` if the channel could not
+    /// be fetched
+    fn fetch_new_videos(&self, db: &Database) -> Result<Option<Vec<DBVideoInfo>>> {
         let chanid = crate::common::YoutubeID {
             id: self.chanid.clone(),
         };
 
         let yt = crate::youtube::YoutubeQuery::new(&chanid);
-        let meta = yt.get_metadata();
-
-        match meta {
-            Ok(meta) => self.update_metadata(&db, &meta)?,
+        match yt.get_metadata() {
+            Ok(meta) => self.update_metadata(db, &meta)?,
             Err(e) => {
                 error!(
                     "Error fetching metadata for {:?} - {} - skipping channel",
                     chanid, e
                 );
                 // Skip to next channel
-                return Ok(());
+                return Ok(None);
             }
         }
 
         let videos = yt.videos();
 
         let seen_videos = self
-            .last_n_video_urls(&db, 50)
+            .last_n_video_urls(db, 50)
             .context("Failed to find latest video URLs")?;
 
         let mut new_videos: Vec<crate::youtube::VideoInfo> = vec![];
@@ -453,31 +730,58 @@ impl Channel {
             new_videos.push(v);
         }
 
+        let mut added = vec![];
         for v in new_videos {
             debug!("Adding {0}", v.title);
-            match self.add_video(&db, &v) {
-                Ok(_) => (),
+            match self.add_video(db, &v) {
+                Ok(dbv) => added.push(dbv),
                 Err(e) => error!("Error adding video {:?} - {:?}", &v, e),
             };
         }
-        Ok(())
+
+        Ok(Some(added))
     }
 }
 
-/// All channels present in database
-pub fn list_channels(db: &Database) -> Result<Vec<Channel>> {
+/// Number of unwatched videos in each channel by SQL ID, excluding ignored videos
+pub fn unwatched_counts(db: &Database) -> Result<HashMap<i64, i64>> {
+    let mut stmt = db.conn.prepare(
+        "SELECT channel, COUNT(*) FROM video
+            WHERE watched=0 AND status != ?1
+            GROUP BY channel",
+    )?;
+    let rows = stmt.query_map(params![VideoStatus::Ignore.as_str()], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+    let mut ret = HashMap::new();
+    for r in rows {
+        let (chan, count) = r?;
+        ret.insert(chan, count);
+    }
+    Ok(ret)
+}
+
+/// Number of videos with each status
+pub fn status_counts(db: &Database) -> Result<Vec<(VideoStatus, i64)>> {
     let mut stmt = db
         .conn
-        .prepare("SELECT id, chanid, service, title, thumbnail FROM channel ORDER BY title")?;
-    let chaniter = stmt.query_map(params![], |row| {
-        Ok(Channel {
-            id: row.get(0)?,
-            chanid: row.get(1)?,
-            service: row.get(2)?,
-            title: row.get(3)?,
-            thumbnail: row.get(4)?,
-        })
-    })?;
+        .prepare("SELECT status, COUNT(*) FROM video GROUP BY status ORDER BY status")?;
+    let rows = stmt.query_map(params![], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?;
+    let mut ret = vec![];
+    for r in rows {
+        let (status, count) = r?;
+        ret.push((VideoStatus::from_str(&status)?, count));
+    }
+    Ok(ret)
+}
+
+/// All channels present in database
+pub fn list_channels(db: &Database) -> Result<Vec<Channel>> {
+    let mut stmt = db.conn.prepare(&format!(
+        "SELECT {} FROM channel ORDER BY title",
+        CHANNEL_COLUMNS
+    ))?;
+    let chaniter = stmt.query_map(params![], Channel::from_row)?;
     let mut ret = vec![];
     for r in chaniter {
         ret.push(r?);
@@ -485,34 +789,211 @@ pub fn list_channels(db: &Database) -> Result<Vec<Channel>> {
     Ok(ret)
 }
 
-pub fn all_videos(db: &Database, limit: i64, page: i64) -> Result<Vec<DBVideoInfo>> {
-    let mapper = |row: &rusqlite::Row| {
-        Ok(DBVideoInfo {
-            id: row.get(0)?,
-            status: row.get(1)?,
-            info: VideoInfo {
-                id: row.get(2)?,
-                url: row.get(3)?,
-                title: row.get(4)?,
-                description: row.get(5)?,
-                thumbnail_url: row.get(6)?,
-                published_at: row.get(7)?,
-            },
-            chanid: row.get(8)?,
-        })
+/// Set status of several videos in a single transaction
+pub fn set_status_many(db: &Database, ids: &[i64], status: VideoStatus) -> Result<()> {
+    let tx = db.conn.unchecked_transaction()?;
+    {
+        let mut stmt = tx.prepare("UPDATE video SET status=?1 WHERE id=?2")?;
+        for id in ids {
+            stmt.execute(params![status.as_str(), id])
+                .context("Failed to update video status")?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Order videos are listed in
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum VideoSort {
+    #[default]
+    Newest,
+    Oldest,
+    Title,
+}
+
+impl VideoSort {
+    pub fn as_str(&self) -> &str {
+        match self {
+            VideoSort::Newest => "newest",
+            VideoSort::Oldest => "oldest",
+            VideoSort::Title => "title",
+        }
+    }
+
+    pub fn from_str(sort: &str) -> Result<Self> {
+        match sort {
+            "newest" => Ok(VideoSort::Newest),
+            "oldest" => Ok(VideoSort::Oldest),
+            "title" => Ok(VideoSort::Title),
+            _ => Err(anyhow::anyhow!("Unknown sort order {:?}", sort)),
+        }
+    }
+
+    fn order_by(&self) -> &str {
+        match self {
+            VideoSort::Newest => "published_at DESC",
+            VideoSort::Oldest => "published_at ASC",
+            VideoSort::Title => "title COLLATE NOCASE ASC, published_at DESC",
+        }
+    }
+}
+
+/// Restricts which videos are returned by `all_videos`, and in what order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VideoFilter {
+    /// Only include videos with one of these statuses, or any status if empty
+    pub statuses: Vec<VideoStatus>,
+    /// Only include videos published at or after this time
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only include videos published before this time
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+    pub sort: VideoSort,
+    /// Only include videos from channels this user (by SQL ID) is subscribed to
+    pub subscribed_by: Option<i64>,
+    /// When filtering by status, treat videos this user has ignored as having the `Ignore`
+    /// status rather than their real one
+    pub ignored_by: Option<i64>,
+}
+
+impl VideoFilter {
+    /// SQL conditions to be combined with `AND`, with the values for their placeholders
+    fn conditions(&self, values: &mut Vec<Value>) -> Vec<String> {
+        let mut conds = vec![];
+        if !self.statuses.is_empty() {
+            let mut placeholders = vec![];
+            for st in &self.statuses {
+                values.push(Value::Text(st.as_str().into()));
+                placeholders.push(format!("?{}", values.len()));
+            }
+            let mut cond = format!("status IN ({})", placeholders.join(", "));
+            if let Some(user) = self.ignored_by {
+                values.push(Value::Integer(user));
+                let ignored = format!(
+                    "(id IN (SELECT video FROM user_video WHERE user=?{} AND ignored=1)
+                        AND status IN ('{}', '{}'))",
+                    values.len(),
+                    VideoStatus::New.as_str(),
+                    VideoStatus::GrabError.as_str(),
+                );
+                cond = if self.statuses.contains(&VideoStatus::Ignore) {
+                    format!("(({} AND NOT {ign}) OR {ign})", cond, ign = ignored)
+                } else {
+                    format!("({} AND NOT {})", cond, ignored)
+                };
+            }
+            conds.push(cond);
+        }
+        if let Some(user) = self.subscribed_by {
+            values.push(Value::Integer(user));
+            conds.push(format!(
+                "channel IN (SELECT channel FROM subscription WHERE user=?{})",
+                values.len()
+            ));
+        }
+        if let Some(since) = self.since {
+            values.push(Value::Text(since.to_rfc3339()));
+            conds.push(format!("published_at >= ?{}", values.len()));
+        }
+        if let Some(until) = self.until {
+            values.push(Value::Text(until.to_rfc3339()));
+            conds.push(format!("published_at < ?{}", values.len()));
+        }
+        conds
+    }
+}
+
+/// Videos matching the filter, from a single channel or all channels if `chanid` is `None`
+fn query_videos(
+    db: &Database,
+    chanid: Option<i64>,
+    filter: &VideoFilter,
+    limit: i64,
+    page: i64,
+) -> Result<Vec<DBVideoInfo>> {
+    let mut values: Vec<Value> = vec![];
+    let mut conds = filter.conditions(&mut values);
+    if let Some(chanid) = chanid {
+        values.push(Value::Integer(chanid));
+        conds.push(format!("channel = ?{}", values.len()));
+    }
+    let where_clause = if conds.is_empty() {
+        "".to_string()
+    } else {
+        format!("WHERE {}", conds.join(" AND "))
     };
+    values.push(Value::Integer(limit));
+    values.push(Value::Integer(limit.saturating_mul(page)));
+
+    let mut q = db.conn.prepare(&format!(
+        "SELECT {}
+            FROM video
+            {}
+            ORDER BY {}
+            LIMIT ?{}
+            OFFSET ?{}
+            ",
+        VIDEO_COLUMNS,
+        where_clause,
+        filter.sort.order_by(),
+        values.len() - 1,
+        values.len(),
+    ))?;
+    let mapped = q.query_map(&values, DBVideoInfo::from_row)?;
 
     let mut ret: Vec<DBVideoInfo> = vec![];
+    for r in mapped {
+        ret.push(r?);
+    }
+    Ok(ret)
+}
+
+pub fn all_videos(
+    db: &Database,
+    filter: &VideoFilter,
+    limit: i64,
+    page: i64,
+) -> Result<Vec<DBVideoInfo>> {
+    query_videos(db, None, filter, limit, page)
+}
+
+/// Paths of every recorded sidecar file
+pub fn sidecar_paths(db: &Database) -> Result<HashSet<PathBuf>> {
+    let mut q = db.conn.prepare("SELECT path FROM sidecar")?;
+    let mapped = q.query_map(params![], |row| row.get::<_, String>(0))?;
 
-    let mut q = db.conn.prepare(
-        "SELECT id, status, video_id, url, title, description, thumbnail, published_at, channel
+    let mut ret = HashSet::new();
+    for r in mapped {
+        ret.insert(PathBuf::from(r?));
+    }
+    Ok(ret)
+}
+
+/// Most recently published videos which have been downloaded, either for a single channel or all
+/// channels if `chanid` is `None`. With `subscriber`, only channels the user with that SQL ID is
+/// subscribed to are included
+pub fn grabbed_videos(
+    db: &Database,
+    chanid: Option<i64>,
+    subscriber: Option<i64>,
+    limit: i64,
+) -> Result<Vec<DBVideoInfo>> {
+    let mut ret: Vec<DBVideoInfo> = vec![];
+
+    let mut q = db.conn.prepare(&format!(
+        "SELECT {}
             FROM video
+            WHERE status=?1 AND (?2 IS NULL OR channel=?2)
+                AND (?4 IS NULL OR channel IN (SELECT channel FROM subscription WHERE user=?4))
             ORDER BY published_at DESC
-            LIMIT ?1
-            OFFSET ?2
+            LIMIT ?3
             ",
+        VIDEO_COLUMNS
+    ))?;
+    let mapped = q.query_map(
+        params![VideoStatus::Grabbed.as_str(), chanid, limit, subscriber],
+        DBVideoInfo::from_row,
     )?;
-    let mapped = q.query_map(params![limit, page * limit], mapper)?;
     for r in mapped {
         ret.push(r?);
     }
@@ -558,7 +1039,7 @@ mod tests {
 
         // Check no videos exist
         {
-            let vids = c.all_videos(&mdb, 50, 0)?;
+            let vids = c.all_videos(&mdb, &VideoFilter::default(), 50, 0)?;
             assert_eq!(vids.len(), 0);
         }
 
@@ -586,7 +1067,7 @@ mod tests {
 
         // Check video now exists
         {
-            let vids = c.all_videos(&mdb, 50, 0)?;
+            let vids = c.all_videos(&mdb, &VideoFilter::default(), 50, 0)?;
             assert_eq!(vids.len(), 1);
             let first = &vids[0].info;
             assert_eq!(first.id, "an id");
@@ -624,4 +1105,209 @@ mod tests {
         }
         Ok(())
     }
+
+    /// Channel with a new video published in 2001 and an older one from 1999
+    fn channel_with_videos(mdb: &Database) -> Result<Channel> {
+        let cid = ChannelID::Youtube(crate::common::YoutubeID {
+            id: "UCUBfKCp83QT19JCUekEdxOQ".into(),
+        });
+        let c = Channel::create(mdb, &cid, "test channel", "")?;
+        for (id, when) in &[
+            ("an id", "2001-12-30T16:39:57Z"),
+            ("old id", "1999-04-01T12:30:01Z"),
+        ] {
+            c.add_video(
+                mdb,
+                &VideoInfo {
+                    id: id.to_string(),
+                    url: format!("http://example.com/watch?v={}", id),
+                    title: "A title!".into(),
+                    description: "".into(),
+                    thumbnail_url: "".into(),
+                    published_at: chrono::DateTime::parse_from_rfc3339(when)?
+                        .with_timezone(&chrono::Utc),
+                },
+            )?;
+        }
+        Ok(c)
+    }
+
+    #[test]
+    fn test_ignore_older_than() -> Result<()> {
+        let mdb = Database::open_in_memory()?;
+        let c = channel_with_videos(&mdb)?;
+
+        // Ignoring videos older than the newest one only changes the older video
+        let vids = c.all_videos(&mdb, &VideoFilter::default(), 50, 0)?;
+        assert_eq!(c.ignore_older_than(&mdb, &vids[0].info.published_at)?, 1);
+        let vids = c.all_videos(&mdb, &VideoFilter::default(), 50, 0)?;
+        assert_eq!(vids[0].status, VideoStatus::New);
+        assert_eq!(vids[1].status, VideoStatus::Ignore);
+
+        // ..and bulk updates change all given videos
+        let ids: Vec<i64> = vids.iter().map(|v| v.id).collect();
+        set_status_many(&mdb, &ids, VideoStatus::Queued)?;
+        for v in c.all_videos(&mdb, &VideoFilter::default(), 50, 0)? {
+            assert_eq!(v.status, VideoStatus::Queued);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_number_in_year() -> Result<()> {
+        let mdb = Database::open_in_memory()?;
+        let c = channel_with_videos(&mdb)?;
+        let add = |id: &str, when: &str| -> Result<DBVideoInfo> {
+            c.add_video(
+                &mdb,
+                &VideoInfo {
+                    id: id.into(),
+                    url: format!("http://example.com/watch?v={}", id),
+                    title: "A title!".into(),
+                    description: "".into(),
+                    thumbnail_url: "".into(),
+                    published_at: chrono::DateTime::parse_from_rfc3339(when)?
+                        .with_timezone(&chrono::Utc),
+                },
+            )
+        };
+        let same_time = add("same time", "2001-12-30T16:39:57Z")?;
+        let earlier = add("earlier", "2001-12-30T08:00:00Z")?;
+
+        let vids = c.all_videos(&mdb, &VideoFilter::default(), 50, 0)?;
+        let number = |id: &str| {
+            vids.iter()
+                .find(|v| v.info.id == id)
+                .unwrap()
+                .number_in_year(&mdb)
+        };
+        assert_eq!(earlier.number_in_year(&mdb)?, 1);
+        assert_eq!(number("an id")?, 2);
+        assert_eq!(same_time.number_in_year(&mdb)?, 3);
+        // Numbering starts again each year
+        assert_eq!(number("old id")?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grabbed_videos() -> Result<()> {
+        let mdb = Database::open_in_memory()?;
+        let c = channel_with_videos(&mdb)?;
+        let other = Channel::create(
+            &mdb,
+            &crate::common::Service::Youtube.get_channel_id("UCother"),
+            "other channel",
+            "",
+        )?;
+        let vids = c.all_videos(&mdb, &VideoFilter::default(), 50, 0)?;
+        vids[1].set_status(&mdb, VideoStatus::Grabbed)?;
+        assert_eq!(grabbed_videos(&mdb, None, None, 10)?.len(), 1);
+        assert_eq!(grabbed_videos(&mdb, Some(other.id), None, 10)?.len(), 0);
+
+        // Only subscribed channels are included for a user
+        let u = crate::users::User::create(&mdb, "someone", "hunter2", false)?;
+        u.subscribe(&mdb, &other)?;
+        assert_eq!(grabbed_videos(&mdb, None, Some(u.id), 10)?.len(), 0);
+        u.subscribe(&mdb, &c)?;
+        let grabbed = grabbed_videos(&mdb, None, Some(u.id), 10)?;
+        assert_eq!(grabbed.len(), 1);
+        assert_eq!(grabbed[0].info.id, "old id");
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_counts() -> Result<()> {
+        let mdb = Database::open_in_memory()?;
+        assert!(status_counts(&mdb)?.is_empty());
+
+        let c = channel_with_videos(&mdb)?;
+        let vids = c.all_videos(&mdb, &VideoFilter::default(), 50, 0)?;
+        vids[1].set_status(&mdb, VideoStatus::Ignore)?;
+        assert_eq!(
+            status_counts(&mdb)?,
+            vec![(VideoStatus::Ignore, 1), (VideoStatus::New, 1)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_video_filter() -> Result<()> {
+        let mdb = Database::open_in_memory()?;
+        let c = channel_with_videos(&mdb)?;
+        let old = &c.all_videos(&mdb, &VideoFilter::default(), 50, 0)?[1];
+        old.set_status(&mdb, VideoStatus::Ignore)?;
+
+        let filter = VideoFilter {
+            statuses: vec![VideoStatus::Ignore],
+            ..Default::default()
+        };
+        let ignored = c.all_videos(&mdb, &filter, 50, 0)?;
+        assert_eq!(ignored.len(), 1);
+        assert_eq!(ignored[0].info.id, "old id");
+
+        let filter = VideoFilter {
+            since: Some(
+                chrono::DateTime::parse_from_rfc3339("2000-01-01T00:00:00Z")?
+                    .with_timezone(&chrono::Utc),
+            ),
+            ..Default::default()
+        };
+        let recent = all_videos(&mdb, &filter, 50, 0)?;
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].info.id, "an id");
+
+        let filter = VideoFilter {
+            sort: VideoSort::Oldest,
+            ..Default::default()
+        };
+        let oldest = all_videos(&mdb, &filter, 1, 0)?;
+        assert_eq!(oldest[0].info.id, "old id");
+        Ok(())
+    }
+
+    #[test]
+    fn test_watched() -> Result<()> {
+        let mdb = Database::open_in_memory()?;
+        let c = channel_with_videos(&mdb)?;
+        let vids = c.all_videos(&mdb, &VideoFilter::default(), 50, 0)?;
+        vids[1].set_status(&mdb, VideoStatus::Ignore)?;
+
+        // Watched state is separate from status, and ignored videos are not counted
+        assert_eq!(unwatched_counts(&mdb)?.get(&c.id), Some(&1));
+        vids[0].set_watch_position(&mdb, 12.5)?;
+        assert_eq!(
+            DBVideoInfo::get_by_sqlid(&mdb, vids[0].id)?.watch_position,
+            12.5
+        );
+        vids[0].set_watched(&mdb, true)?;
+        let v = DBVideoInfo::get_by_sqlid(&mdb, vids[0].id)?;
+        assert!(v.watched);
+        assert_eq!(v.watch_position, 0.0);
+        assert_eq!(v.status, VideoStatus::New);
+        assert_eq!(unwatched_counts(&mdb)?.get(&c.id), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_migration_rolled_back() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let migrations = &[
+            "CREATE TABLE a (x INTEGER);",
+            "CREATE TABLE b (x INTEGER); INSERT INTO missing VALUES (1);",
+        ];
+        assert!(Database::apply_migrations(&conn, migrations).is_err());
+
+        let version: i64 = conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        assert_eq!(version, 1);
+        let tables: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='b'",
+            params![],
+            |row| row.get(0),
+        )?;
+        assert_eq!(tables, 0);
+
+        // Fixed migration can then be applied
+        Database::apply_migrations(&conn, &[migrations[0], "CREATE TABLE b (x INTEGER);"])?;
+        Ok(())
+    }
 }
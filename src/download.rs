@@ -1,46 +1,353 @@
+use std::collections::HashMap;
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::Mutex;
 
 use anyhow::{Context, Result};
-use log::debug;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use thiserror::Error;
 
-use crate::config::Config;
+use crate::common::{lock, VideoStatus};
+use crate::config::{Config, QualityProfile};
+use crate::db::{DBVideoInfo, Database};
 use crate::youtube::VideoInfo;
 
-pub fn download(vid: &VideoInfo) -> Result<()> {
-    let cfg = Config::load();
+/// Downloader process currently running for a video
+struct RunningDownload {
+    pid: u32,
+    cancelled: bool,
+}
+
+lazy_static! {
+    /// Downloads in progress, keyed by video ID
+    static ref RUNNING: Mutex<HashMap<String, RunningDownload>> = Mutex::new(HashMap::new());
+}
+
+/// Removes the entry from `RUNNING` however the download finishes, as long as it is still the
+/// one for the process this download started
+struct RunningGuard<'a> {
+    video_id: &'a str,
+    pid: u32,
+}
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        let mut running = lock(&RUNNING);
+        if running.get(self.video_id).map(|r| r.pid) == Some(self.pid) {
+            running.remove(self.video_id);
+        }
+    }
+}
+
+/// Broad category of a failed download, used to decide if it is worth retrying
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DownloadErrorKind {
+    /// Video is private, removed or otherwise permanently unavailable
+    Unavailable,
+    /// Video is blocked in this country
+    GeoBlocked,
+    /// Service is limiting the number of requests
+    RateLimited,
+    /// Connection problems
+    Network,
+    /// Stopped by the user
+    Cancelled,
+    /// Anything unrecognised
+    Other,
+}
+
+impl DownloadErrorKind {
+    /// Categorise a youtube-dl error message
+    pub fn classify(message: &str) -> DownloadErrorKind {
+        let msg = message.to_lowercase();
+        let has = |patterns: &[&str]| patterns.iter().any(|p| msg.contains(p));
+
+        if has(&[
+            "available in your country",
+            "blocked it in your country",
+            "geo restriction",
+            "geo-restricted",
+            "georestricted",
+        ]) {
+            DownloadErrorKind::GeoBlocked
+        } else if has(&["http error 429", "too many requests"]) {
+            DownloadErrorKind::RateLimited
+        } else if has(&[
+            "private video",
+            "video unavailable",
+            "this video is unavailable",
+            "has been removed",
+            "has been terminated",
+            "copyright",
+            "members-only",
+            "sign in to confirm your age",
+        ]) {
+            DownloadErrorKind::Unavailable
+        } else if has(&[
+            "timed out",
+            "connection reset",
+            "connection refused",
+            "network is unreachable",
+            "temporary failure in name resolution",
+            "unable to download webpage",
+            "urlopen error",
+            "incompleteread",
+            "http error 500",
+            "http error 502",
+            "http error 503",
+            "http error 504",
+        ]) {
+            DownloadErrorKind::Network
+        } else {
+            DownloadErrorKind::Other
+        }
+    }
+
+    /// Whether the error is likely to go away if the download is retried later
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            DownloadErrorKind::RateLimited | DownloadErrorKind::Network
+        )
+    }
+}
+
+/// Error from youtube-dl failing to download a video
+#[derive(Error, Debug)]
+#[error("{message}")]
+pub struct DownloadFailure {
+    pub kind: DownloadErrorKind,
+    /// Most relevant line from youtube-dl's error output
+    pub message: String,
+}
+
+/// Find the output filename from a line of youtube-dl's output, if it mentions one
+fn parse_destination(line: &str) -> Option<PathBuf> {
+    let line = line.trim();
+    if let Some(dest) = line.strip_prefix("[download] Destination: ") {
+        // Start of a new download
+        Some(PathBuf::from(dest))
+    } else if line.starts_with("[download] ") && line.ends_with(" has already been downloaded") {
+        // Previously completed download
+        let end = line.len() - " has already been downloaded".len();
+        Some(PathBuf::from(&line["[download] ".len()..end]))
+    } else if line.starts_with("[ffmpeg] Merging formats into \"") && line.ends_with('"') {
+        // Separate video and audio streams being combined into final file
+        let start = "[ffmpeg] Merging formats into \"".len();
+        Some(PathBuf::from(&line[start..line.len() - 1]))
+    } else {
+        None
+    }
+}
+
+/// Parse the percentage from a youtube-dl progress line such as
+/// `[download]  45.3% of 10.00MiB at  1.00MiB/s ETA 00:05`
+fn parse_progress(line: &str) -> Option<f32> {
+    let line = line.trim();
+    if !line.starts_with("[download]") {
+        return None;
+    }
+    let pct = line["[download]".len()..].trim_start().split('%').next()?;
+    pct.trim().parse().ok()
+}
+
+/// Interesting information from a line of a downloader's output
+#[derive(Debug, PartialEq)]
+pub enum OutputLine {
+    /// Percentage of the current file downloaded
+    Progress(f32),
+    /// File being written to
+    Destination(PathBuf),
+}
+
+/// Which program is used to download videos
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DownloaderKind {
+    YoutubeDl,
+    YtDlp,
+    /// Script which pretends to be youtube-dl, for testing without network access. Only built
+    /// into tests, as it is found through the location of the source tree
+    #[cfg(test)]
+    Fake,
+}
+
+impl DownloaderKind {
+    pub fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "youtube-dl" => Ok(DownloaderKind::YoutubeDl),
+            "yt-dlp" => Ok(DownloaderKind::YtDlp),
+            #[cfg(test)]
+            "fake" => Ok(DownloaderKind::Fake),
+            _ => Err(anyhow::anyhow!("Unknown downloader {:?}", name)),
+        }
+    }
+}
+
+/// Abstraction over programs which can download a video
+pub trait Downloader {
+    /// Program to run
+    fn binary(&self) -> &Path;
+
+    /// Arguments required for `parse_line` to understand the output
+    fn output_args(&self) -> Vec<String>;
+
+    /// Find any interesting information in a line of stdout
+    fn parse_line(&self, line: &str) -> Option<OutputLine>;
+}
+
+/// The original youtube-dl, or anything with compatible output
+pub struct YoutubeDl {
+    binary: PathBuf,
+}
+
+impl Downloader for YoutubeDl {
+    fn binary(&self) -> &Path {
+        &self.binary
+    }
+
+    fn output_args(&self) -> Vec<String> {
+        vec!["--newline".into()]
+    }
+
+    fn parse_line(&self, line: &str) -> Option<OutputLine> {
+        if let Some(dest) = parse_destination(line) {
+            Some(OutputLine::Destination(dest))
+        } else {
+            parse_progress(line).map(OutputLine::Progress)
+        }
+    }
+}
+
+/// The yt-dlp fork, using its templated progress output and printing the final filename
+pub struct YtDlp {
+    binary: PathBuf,
+}
+
+impl Downloader for YtDlp {
+    fn binary(&self) -> &Path {
+        &self.binary
+    }
+
+    fn output_args(&self) -> Vec<String> {
+        vec![
+            "--newline".into(),
+            // Printing the filename implies --quiet, so progress must be re-enabled
+            "--progress".into(),
+            "--progress-template".into(),
+            "download:[vidl-progress] %(progress._percent_str)s".into(),
+            "--print".into(),
+            "after_move:[vidl-file] %(filepath)s".into(),
+        ]
+    }
+
+    fn parse_line(&self, line: &str) -> Option<OutputLine> {
+        let line = line.trim();
+        if let Some(pct) = line.strip_prefix("[vidl-progress]") {
+            let pct = pct.trim().trim_end_matches('%');
+            pct.parse().ok().map(OutputLine::Progress)
+        } else if let Some(dest) = line.strip_prefix("[vidl-file] ") {
+            Some(OutputLine::Destination(PathBuf::from(dest)))
+        } else {
+            parse_destination(line).map(OutputLine::Destination)
+        }
+    }
+}
+
+/// Create the downloader selected in the config
+pub fn downloader(cfg: &Config) -> Box<dyn Downloader> {
+    let path = cfg.downloader_path.clone();
+    match cfg.downloader {
+        DownloaderKind::YoutubeDl => Box::new(YoutubeDl {
+            binary: path.unwrap_or_else(|| "youtube-dl".into()),
+        }),
+        DownloaderKind::YtDlp => Box::new(YtDlp {
+            binary: path.unwrap_or_else(|| "yt-dlp".into()),
+        }),
+        #[cfg(test)]
+        DownloaderKind::Fake => Box::new(YoutubeDl {
+            binary: path.unwrap_or_else(|| {
+                concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/fake-downloader.sh").into()
+            }),
+        }),
+    }
+}
+
+/// Download given video into `output_dir`, returning the location of the downloaded file (if it
+/// could be determined from the downloader's output)
+pub fn download(
+    vid: &VideoInfo,
+    cfg: &Config,
+    profile: &QualityProfile,
+    output_dir: &Path,
+) -> Result<Option<PathBuf>> {
+    let dl = downloader(cfg);
 
     // Ensure output folder exists
-    std::fs::create_dir_all(&cfg.download_dir).context("Failed to make output folder")?;
+    std::fs::create_dir_all(output_dir).context("Failed to make output folder")?;
 
-    let output_template = &cfg.download_dir.join(cfg.filename_format);
+    // Directory names may contain `%` which must not be interpreted as part of the template
+    let output_template =
+        PathBuf::from(output_dir.to_string_lossy().replace('%', "%%")).join(&cfg.filename_format);
 
     // Prepare command arguments
-    let mut args: Vec<&str> = vec![];
+    let mut args: Vec<String> = vec![];
 
-    // First option required by progress parser
-    args.push("--newline");
-    args.push("--output");
-    args.push(output_template.to_str().unwrap());
+    // First options required by output parser
+    args.extend(dl.output_args());
+    args.push("--output".into());
+    args.push(output_template.to_string_lossy().into());
 
-    // Then options from config
-    args.extend(
-        cfg.extra_youtubedl_args
-            .iter()
-            .map(|x: &String| -> &str { x.as_ref() }),
-    );
+    // Then options from config and the quality profile
+    args.extend(cfg.extra_youtubedl_args.iter().cloned());
+    args.extend(profile.downloader_args());
 
     // Final arg is video URL
-    args.push(&vid.url);
+    args.push(vid.url.clone());
 
-    debug!("Running youtube-dl with args {:#?}", args);
+    debug!("Running {:?} with args {:#?}", dl.binary(), args);
 
-    let mut child = Command::new("youtube-dl")
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .args(args)
-        .spawn()?;
+    let mut cmd = Command::new(dl.binary());
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).args(args);
+
+    // Start in a new process group, so processes started by the downloader (like ffmpeg) can
+    // be killed along with it on cancellation
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    // Checked and recorded under one lock, so the same video is never downloaded twice at once
+    // (which would leave cancelling it to kill only one of the downloads)
+    let mut running = lock(&RUNNING);
+    if running.contains_key(&vid.id) {
+        return Err(DownloadFailure {
+            kind: DownloadErrorKind::Other,
+            message: "Video is already being downloaded".into(),
+        }
+        .into());
+    }
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("Failed to run downloader {:?}", dl.binary()))?;
+    running.insert(
+        vid.id.clone(),
+        RunningDownload {
+            pid: child.id(),
+            cancelled: false,
+        },
+    );
+    std::mem::drop(running);
+    let _guard = RunningGuard {
+        video_id: &vid.id,
+        pid: child.id(),
+    };
 
+    let mut destination: Option<PathBuf> = None;
+    let err_lines: Vec<String>;
     {
         let stdout = child
             .stdout
@@ -55,23 +362,255 @@ pub fn download(vid: &VideoInfo) -> Result<()> {
             .ok_or(anyhow::anyhow!("Failed to find thing"))?;
         let reader_err = BufReader::new(stderr);
 
-        reader
-            .lines()
-            .filter_map(|line| line.ok())
-            .for_each(|line| println!("{}", line));
+        // Collect stderr in separate thread so neither pipe can fill up and block the downloader
+        let err_thread = std::thread::spawn(move || {
+            reader_err
+                .lines()
+                .map_while(Result::ok)
+                .inspect(|line| println!("ERR: {}", line))
+                .collect::<Vec<String>>()
+        });
 
-        reader_err
-            .lines()
-            .filter_map(|line| line.ok())
-            .for_each(|line| println!("ERR: {}", line));
+        reader.lines().map_while(Result::ok).for_each(|line| {
+            println!("{}", line);
+            match dl.parse_line(&line) {
+                Some(OutputLine::Destination(dest)) => destination = Some(dest),
+                Some(OutputLine::Progress(pct)) => debug!("{} at {}%", &vid.id, pct),
+                None => (),
+            }
+        });
+
+        err_lines = err_thread.join().unwrap_or_default();
     }
     let exit = child.wait()?;
+
+    let cancelled = lock(&RUNNING)
+        .get(&vid.id)
+        .map(|r| r.cancelled)
+        .unwrap_or(false);
+    if cancelled {
+        return Err(DownloadFailure {
+            kind: DownloadErrorKind::Cancelled,
+            message: "Download cancelled".into(),
+        }
+        .into());
+    }
+
     if !exit.success() {
-        return Err(anyhow::anyhow!(
-            "youtube-dl exited with non-zero exit status {}",
-            exit
-        ));
+        // Prefer the line marked as the error, falling back to any output
+        let message = err_lines
+            .iter()
+            .rev()
+            .find(|l| l.starts_with("ERROR:"))
+            .or_else(|| err_lines.last())
+            .cloned()
+            .unwrap_or_else(|| format!("Downloader exited with non-zero exit status {}", exit));
+        return Err(DownloadFailure {
+            kind: DownloadErrorKind::classify(&err_lines.join("\n")),
+            message,
+        }
+        .into());
     }
 
+    Ok(destination)
+}
+
+/// Kill a process started in its own process group, along with everything it started.
+/// Returns `false` if the kill command failed
+pub fn kill_tree(pid: u32) -> Result<bool> {
+    #[cfg(unix)]
+    let status = Command::new("kill")
+        .args(["-TERM", "--", &format!("-{}", pid)])
+        .status()?;
+    #[cfg(not(unix))]
+    let status = Command::new("taskkill")
+        .args(&["/F", "/T", "/PID", &pid.to_string()])
+        .status()?;
+
+    Ok(status.success())
+}
+
+/// Kill the downloader process for the given video ID, and anything it started. Returns `false`
+/// if the video is not being downloaded by this process
+fn kill(video_id: &str) -> Result<bool> {
+    let mut running = lock(&RUNNING);
+    let entry = match running.get_mut(video_id) {
+        Some(e) => e,
+        None => return Ok(false),
+    };
+    entry.cancelled = true;
+
+    if !kill_tree(entry.pid)? {
+        warn!("Failed to kill downloader process {}", entry.pid);
+    }
+    Ok(true)
+}
+
+/// Cancel a queued or in-progress download, returning the video to `New`. Queued videos are
+/// skipped when a worker reaches them. With `cleanup`, partially downloaded files are deleted
+pub fn cancel(db: &Database, cfg: &Config, video: &DBVideoInfo, cleanup: bool) -> Result<()> {
+    match video.status {
+        VideoStatus::Queued | VideoStatus::Downloading => (),
+        other => {
+            return Err(anyhow::anyhow!(
+                "Video is not queued or downloading (status {:?})",
+                other
+            ))
+        }
+    }
+    video.set_status(db, VideoStatus::New)?;
+
+    if kill(&video.info.id)? {
+        info!("Killed downloader for {:?}", &video.info);
+        // Give downloader a moment to exit, so it does not recreate any files being cleaned up
+        std::thread::sleep(std::time::Duration::from_millis(500));
+    }
+
+    if cleanup {
+        for path in crate::scan::partial_files(cfg, &video.info.id)? {
+            info!("Removing partial download {:?}", &path);
+            std::fs::remove_file(&path).with_context(|| format!("Failed to remove {:?}", &path))?;
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_destination() {
+        assert_eq!(
+            parse_destination("[download] Destination: ./download/a__20200101_b__abc.mp4"),
+            Some(PathBuf::from("./download/a__20200101_b__abc.mp4"))
+        );
+        assert_eq!(
+            parse_destination("[download] ./download/x.webm has already been downloaded"),
+            Some(PathBuf::from("./download/x.webm"))
+        );
+        assert_eq!(
+            parse_destination("[ffmpeg] Merging formats into \"./download/x.mkv\""),
+            Some(PathBuf::from("./download/x.mkv"))
+        );
+        assert_eq!(
+            parse_destination("[download]  45.3% of 10.00MiB at  1.00MiB/s ETA 00:05"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_classify_error() {
+        use DownloadErrorKind::*;
+        let c = DownloadErrorKind::classify;
+        assert_eq!(
+            c("ERROR: Private video\nSign in if you've been granted access to this video"),
+            Unavailable
+        );
+        assert_eq!(
+            c("ERROR: Video unavailable\nThis video has been removed by the uploader"),
+            Unavailable
+        );
+        assert_eq!(
+            c("ERROR: The uploader has not made this video available in your country."),
+            GeoBlocked
+        );
+        assert_eq!(
+            c("ERROR: Unable to download webpage: HTTP Error 429: Too Many Requests"),
+            RateLimited
+        );
+        assert_eq!(c("ERROR: Unable to download webpage: <urlopen error [Errno -3] Temporary failure in name resolution>"), Network);
+        assert_eq!(c("ERROR: something strange happened"), Other);
+
+        assert!(RateLimited.is_transient());
+        assert!(Network.is_transient());
+        assert!(!Unavailable.is_transient());
+        assert!(!GeoBlocked.is_transient());
+    }
+
+    #[test]
+    fn test_parse_progress() {
+        let ytdl = YoutubeDl {
+            binary: "youtube-dl".into(),
+        };
+        assert_eq!(
+            ytdl.parse_line("[download]  45.3% of 10.00MiB at  1.00MiB/s ETA 00:05"),
+            Some(OutputLine::Progress(45.3))
+        );
+        assert_eq!(ytdl.parse_line("[youtube] abc: Downloading webpage"), None);
+
+        let ytdlp = YtDlp {
+            binary: "yt-dlp".into(),
+        };
+        assert_eq!(
+            ytdlp.parse_line("[vidl-progress]   7.5%"),
+            Some(OutputLine::Progress(7.5))
+        );
+        assert_eq!(
+            ytdlp.parse_line("[vidl-file] /tmp/download/x__abc.mkv"),
+            Some(OutputLine::Destination("/tmp/download/x__abc.mkv".into()))
+        );
+    }
+
+    #[test]
+    fn test_fake_download() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("vidl-test-download-{}", std::process::id()));
+
+        let mut cfg = Config::load_from(&dir.join("config"))?;
+        cfg.download_dir = dir.clone();
+        cfg.downloader = DownloaderKind::Fake;
+        cfg.downloader_path = None;
+
+        // Different ID to the worker test, which may be downloading at the same time
+        let mut vid = VideoInfo {
+            id: "def456".into(),
+            url: "http://youtube.com/watch?v=def456".into(),
+            title: "A title".into(),
+            description: "".into(),
+            thumbnail_url: "http://example.com/thumb.jpg".into(),
+            published_at: chrono::Utc::now(),
+        };
+        let profile = QualityProfile::default();
+        let path = download(&vid, &cfg, &profile, &dir)?.expect("Destination not found in output");
+        assert!(path.exists());
+        assert!(path.to_string_lossy().contains("def456"));
+
+        // Fake downloader fails for URL's containing "fail"
+        vid.url = "http://youtube.com/watch?v=fail".into();
+        let err = download(&vid, &cfg, &profile, &dir).unwrap_err();
+        let failure = err.downcast_ref::<DownloadFailure>().unwrap();
+        assert_eq!(failure.kind, DownloadErrorKind::Unavailable);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_download() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("vidl-test-dup-{}", std::process::id()));
+        let mut cfg = Config::load_from(&dir.join("config"))?;
+        cfg.download_dir = dir.clone();
+        cfg.downloader = DownloaderKind::Fake;
+
+        let vid = VideoInfo {
+            id: "dup789".into(),
+            url: "http://youtube.com/watch?v=dup789".into(),
+            title: "A title".into(),
+            description: "".into(),
+            thumbnail_url: "".into(),
+            published_at: chrono::Utc::now(),
+        };
+        lock(&RUNNING).insert(
+            vid.id.clone(),
+            RunningDownload {
+                pid: 1,
+                cancelled: false,
+            },
+        );
+        let err = download(&vid, &cfg, &QualityProfile::default(), &dir).unwrap_err();
+        assert!(format!("{}", err).contains("already being downloaded"));
+        // ..leaving the other download's entry in place
+        assert_eq!(lock(&RUNNING).remove(&vid.id).map(|r| r.pid), Some(1));
+        Ok(())
+    }
+}
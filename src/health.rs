@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::config::Config;
+use crate::worker::PoolHealth;
+
+/// Outcome of checking one thing vidl depends on
+#[derive(Serialize, Debug)]
+pub struct Probe {
+    pub name: &'static str,
+    pub ok: bool,
+    /// What was found, or why the probe failed. Only logged, as it includes paths and the
+    /// readiness endpoint is available without logging in
+    #[serde(skip)]
+    pub detail: String,
+}
+
+impl Probe {
+    fn new(name: &'static str, result: Result<String>) -> Probe {
+        match result {
+            Ok(detail) => Probe {
+                name,
+                ok: true,
+                detail,
+            },
+            Err(e) => Probe {
+                name,
+                ok: false,
+                detail: format!("{:#}", e),
+            },
+        }
+    }
+}
+
+/// Whether vidl can do its job, with the result of each probe
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub probes: Vec<Probe>,
+}
+
+/// Run every probe. The others depend on the config, so are skipped if it cannot be loaded
+pub fn check(pool: &PoolHealth) -> Readiness {
+    let probes = match Config::load() {
+        Ok(cfg) => vec![
+            Probe::new("config", Ok("Loaded".into())),
+            Probe::new("database", check_database(&cfg)),
+            Probe::new("download_dir", check_download_dir(&cfg)),
+            Probe::new("downloader", check_downloader(&cfg)),
+            Probe::new("workers", check_workers(pool)),
+        ],
+        Err(e) => vec![Probe::new("config", Err(e))],
+    };
+    Readiness {
+        ready: probes.iter().all(|p| p.ok),
+        probes,
+    }
+}
+
+fn check_database(cfg: &Config) -> Result<String> {
+    let db = crate::db::Database::open(cfg)?;
+    // Taking the write lock fails if the file is read-only or locked by something else, without
+    // changing anything
+    db.conn
+        .execute_batch("BEGIN IMMEDIATE; ROLLBACK;")
+        .context("Database is not writable")?;
+    Ok(format!("{:?} is writable", cfg.db_filepath()))
+}
+
+fn check_download_dir(cfg: &Config) -> Result<String> {
+    let dir = &cfg.download_dir;
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    let probe = dir.join(".vidl-readyz");
+    std::fs::write(&probe, b"").with_context(|| format!("{:?} is not writable", dir))?;
+    let _ = std::fs::remove_file(&probe);
+
+    let free = fs2::available_space(dir)
+        .with_context(|| format!("Failed to get free space of {:?}", dir))?;
+    if free < cfg.min_free_space {
+        return Err(anyhow::anyhow!(
+            "Only {} MiB free in {:?}, need {} MiB",
+            free / 1024 / 1024,
+            dir,
+            cfg.min_free_space / 1024 / 1024
+        ));
+    }
+    Ok(format!("{} MiB free in {:?}", free / 1024 / 1024, dir))
+}
+
+fn check_downloader(cfg: &Config) -> Result<String> {
+    let binary = crate::download::downloader(cfg).binary().to_path_buf();
+    let found =
+        find_binary(&binary).ok_or_else(|| anyhow::anyhow!("{:?} not found on PATH", binary))?;
+    Ok(format!("Found {:?}", found))
+}
+
+/// Locate a program the way running it would: paths are used as-is, bare names are looked up
+/// in each `PATH` directory
+fn find_binary(binary: &Path) -> Option<PathBuf> {
+    if binary.components().count() > 1 {
+        return Some(binary.to_path_buf()).filter(|p| p.is_file());
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(binary))
+        .find(|p| p.is_file())
+}
+
+fn check_workers(pool: &PoolHealth) -> Result<String> {
+    if pool.alive < pool.workers.len() {
+        return Err(anyhow::anyhow!(
+            "Only {} of {} worker threads running",
+            pool.alive,
+            pool.workers.len()
+        ));
+    }
+    Ok(format!(
+        "{} worker threads running, {} busy",
+        pool.alive, pool.active
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_binary() {
+        assert!(find_binary(Path::new("sh")).is_some());
+        assert!(find_binary(Path::new("/bin/sh")).is_some());
+        assert!(find_binary(Path::new("vidl-no-such-program")).is_none());
+        assert!(find_binary(Path::new("/no/such/program")).is_none());
+    }
+
+    #[test]
+    fn test_probe_json() {
+        let probe = Probe::new(
+            "database",
+            Err(anyhow::anyhow!("/secret/vidl.sqlite3 is locked")),
+        );
+        assert_eq!(
+            serde_json::to_value(&probe).unwrap(),
+            serde_json::json!({"name": "database", "ok": false})
+        );
+    }
+}
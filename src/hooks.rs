@@ -0,0 +1,200 @@
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+
+use crate::common::VideoStatus;
+use crate::config::Config;
+use crate::db::{Channel, DBVideoInfo};
+
+/// Command run after a video is downloaded
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    /// Program and its arguments
+    pub command: Vec<String>,
+    /// Kill the command if it runs longer than this
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Mark the video as `GrabError` if the command fails, instead of only logging it
+    #[serde(default)]
+    pub fail_on_error: bool,
+}
+
+fn default_timeout_secs() -> u64 {
+    300
+}
+
+/// How long to keep logging a hook's output after it exits, in case something it started
+/// still holds the output pipes open
+const OUTPUT_GRACE: Duration = Duration::from_secs(2);
+
+/// Read lines from output stream into log in a separate thread. The thread holds `done`
+/// until the stream closes, so the receiver sees a disconnect once all readers finish
+fn log_output<R: Read + Send + 'static>(
+    name: String,
+    stream: Option<R>,
+    is_stderr: bool,
+    done: mpsc::Sender<()>,
+) {
+    let stream = match stream {
+        Some(s) => s,
+        None => return,
+    };
+    std::thread::spawn(move || {
+        let _done = done;
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            if is_stderr {
+                warn!("Hook {}: {}", name, line);
+            } else {
+                info!("Hook {}: {}", name, line);
+            }
+        }
+    });
+}
+
+/// Run a single hook with the given environment variables, waiting for it to finish
+fn run_hook(hook: &Hook, env: &[(&str, String)]) -> Result<()> {
+    let program = hook
+        .command
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("Hook has empty command"))?;
+
+    let mut cmd = Command::new(program);
+    cmd.args(&hook.command[1..])
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Own process group so anything the hook starts is also killed on timeout
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("Failed to run hook {:?}", program))?;
+
+    let (done_tx, done_rx) = mpsc::channel();
+    log_output(program.clone(), child.stdout.take(), false, done_tx.clone());
+    log_output(program.clone(), child.stderr.take(), true, done_tx);
+
+    let deadline = Instant::now() + Duration::from_secs(hook.timeout_secs);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() > deadline {
+            if !crate::download::kill_tree(child.id())? {
+                child.kill()?;
+            }
+            child.wait()?;
+            break None;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    // Readers that are still running after the grace period are left detached
+    let output_deadline = Instant::now() + OUTPUT_GRACE;
+    loop {
+        let left = output_deadline.saturating_duration_since(Instant::now());
+        match done_rx.recv_timeout(left) {
+            Ok(()) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                warn!("Hook {:?} left its output open after exiting", program);
+                break;
+            }
+        }
+    }
+
+    match status {
+        None => Err(anyhow::anyhow!(
+            "Hook {:?} timed out after {} seconds",
+            program,
+            hook.timeout_secs
+        )),
+        Some(s) if !s.success() => Err(anyhow::anyhow!("Hook {:?} failed with {}", program, s)),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Run all configured hooks for a downloaded video, passing details of it in environment
+/// variables. Failing hooks are logged, and only cause an error if `fail_on_error` is set
+pub fn run_hooks(
+    cfg: &Config,
+    chan: &Channel,
+    video: &DBVideoInfo,
+    path: Option<&Path>,
+    status: VideoStatus,
+) -> Result<()> {
+    let env: Vec<(&str, String)> = vec![
+        ("VIDL_VIDEO_ID", video.info.id.clone()),
+        ("VIDL_VIDEO_URL", video.info.url.clone()),
+        ("VIDL_TITLE", video.info.title.clone()),
+        ("VIDL_PUBLISHED", video.info.published_at.to_rfc3339()),
+        ("VIDL_CHANNEL", chan.title.clone()),
+        ("VIDL_CHANNEL_ID", chan.chanid.clone()),
+        (
+            "VIDL_FILE",
+            path.map(|p| p.to_string_lossy().into()).unwrap_or_default(),
+        ),
+        ("VIDL_STATUS", format!("{:?}", status)),
+    ];
+
+    for hook in &cfg.hooks {
+        info!("Running hook {:?} for {:?}", &hook.command, &video.info);
+        if let Err(e) = run_hook(hook, &env) {
+            if hook.fail_on_error {
+                return Err(e);
+            }
+            error!("{}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sh(script: &str, timeout_secs: u64) -> Hook {
+        Hook {
+            command: vec!["sh".into(), "-c".into(), script.into()],
+            timeout_secs,
+            fail_on_error: true,
+        }
+    }
+
+    #[test]
+    fn test_run_hook() {
+        let env = vec![("VIDL_TITLE", "A title".to_string())];
+
+        assert!(run_hook(&sh("test \"$VIDL_TITLE\" = \"A title\"", 10), &env).is_ok());
+        assert!(run_hook(&sh("echo oh no >&2; exit 1", 10), &env).is_err());
+
+        let start = Instant::now();
+        let err = run_hook(&sh("sleep 10", 1), &env).unwrap_err();
+        assert!(format!("{}", err).contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_run_hook_detached_child() {
+        // Child escapes the hook's process group but keeps its output pipes open
+        let start = Instant::now();
+        let err = run_hook(&sh("setsid sleep 30 & sleep 10", 1), &[]).unwrap_err();
+        assert!(format!("{}", err).contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let start = Instant::now();
+        assert!(run_hook(&sh("setsid sleep 30 &", 10), &[]).is_ok());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
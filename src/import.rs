@@ -0,0 +1,79 @@
+use anyhow::Result;
+
+use crate::common::ChannelID;
+use crate::db::{Channel, Database};
+
+/// Result of importing channels from another application
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// Nothing was changed, `added` lists channels which would have been added
+    pub dry_run: bool,
+    /// Titles of newly added channels
+    pub added: Vec<String>,
+    /// Titles of channels which were already added
+    pub existing: Vec<String>,
+    /// Entries which could not be matched to a channel, with the reason why
+    pub unresolved: Vec<(String, String)>,
+}
+
+impl ImportReport {
+    pub fn new(dry_run: bool) -> ImportReport {
+        ImportReport {
+            dry_run,
+            ..Default::default()
+        }
+    }
+
+    /// Record the outcome of `add_channel` for the entry described by `label`
+    pub fn record(&mut self, label: &str, result: Result<(String, bool)>) {
+        match result {
+            Ok((title, true)) => self.added.push(title),
+            Ok((title, false)) => self.existing.push(title),
+            Err(e) => self.unresolved.push((label.into(), format!("{:#}", e))),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let mut lines = vec![format!(
+            "{} {} channels, {} already present, {} could not be resolved",
+            if self.dry_run { "Would add" } else { "Added" },
+            self.added.len(),
+            self.existing.len(),
+            self.unresolved.len()
+        )];
+        if self.dry_run {
+            for title in &self.added {
+                lines.push(format!("  + {}", title));
+            }
+        }
+        for (label, reason) in &self.unresolved {
+            lines.push(format!("  {} - {}", label, reason));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Add a channel unless it already exists, returning its title and whether it was newly added.
+/// With `dry_run` nothing is fetched or created, and `title` (if known) is reported for new
+/// channels
+pub fn add_channel(
+    db: &Database,
+    cid: &ChannelID,
+    title: Option<&str>,
+    dry_run: bool,
+) -> Result<(String, bool)> {
+    if let Ok(c) = Channel::get(db, cid) {
+        return Ok((c.title, false));
+    }
+    if dry_run {
+        return Ok((title.unwrap_or(cid.id_str()).into(), true));
+    }
+    match cid {
+        ChannelID::Youtube(ytid) => {
+            let meta = crate::youtube::YoutubeQuery::new(ytid).get_metadata()?;
+            let c = Channel::create(db, cid, &meta.title, &meta.thumbnail)?;
+            Ok((c.title, true))
+        }
+        ChannelID::Vimeo(_) => Err(anyhow::anyhow!("Not yet implemented")),
+    }
+}
@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use askama::Template;
+use chrono::Datelike;
+use log::{debug, warn};
+
+use crate::common::Service;
+use crate::config::Config;
+use crate::db::{Channel, DBVideoInfo, Database};
+
+/// How downloaded files are organised within the download directory
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    /// All files directly in the download directory, named by `filename_format`
+    Flat,
+    /// Arranged as `Channel/Season YYYY/<filename_format>` with `.nfo` files, as understood by
+    /// media servers like Jellyfin and Kodi. Each channel is a "show" and each year a "season"
+    MediaServer,
+}
+
+#[derive(Template)]
+#[template(path = "tvshow.nfo", escape = "xml")]
+struct ShowInfoTemplate<'a> {
+    title: &'a str,
+    plot: &'a str,
+    service: &'a str,
+    chanid: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "episode.nfo", escape = "xml")]
+struct EpisodeInfoTemplate<'a> {
+    title: &'a str,
+    show: &'a str,
+    season: i32,
+    episode: u32,
+    plot: &'a str,
+    aired: String,
+    service: &'a str,
+    id: &'a str,
+    thumb: &'a str,
+}
+
+/// Make a channel title safe to use as a directory name
+fn sanitise_dirname(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    cleaned.trim_matches(|c| c == '.' || c == ' ').to_string()
+}
+
+/// Directory for a channel's files in the media server layout
+fn show_dir(cfg: &Config, chan: &Channel) -> PathBuf {
+    let name = sanitise_dirname(&chan.title);
+    let name = if name.is_empty() {
+        sanitise_dirname(&chan.chanid)
+    } else {
+        name
+    };
+    cfg.download_dir.join(name)
+}
+
+/// Directory the given video should be downloaded into
+pub fn output_dir(cfg: &Config, chan: &Channel, video: &DBVideoInfo) -> PathBuf {
+    match cfg.layout {
+        Layout::Flat => cfg.download_dir.clone(),
+        Layout::MediaServer => {
+            show_dir(cfg, chan).join(format!("Season {}", video.info.published_at.year()))
+        }
+    }
+}
+
+/// Show information written into each channel's directory
+const SHOW_NFO: &str = "tvshow.nfo";
+const SHOW_POSTER: &str = "poster.jpg";
+
+/// Whether a file name is one written for a whole show rather than a single video
+pub fn is_show_file(name: &str) -> bool {
+    name == SHOW_NFO || name == SHOW_POSTER
+}
+
+/// Write `tvshow.nfo` and `poster.jpg` for the channel, if they do not already exist
+fn write_show_info(cfg: &Config, chan: &Channel) -> Result<()> {
+    let dir = show_dir(cfg, chan);
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", &dir))?;
+
+    let nfo_path = dir.join(SHOW_NFO);
+    if !nfo_path.exists() {
+        // Channel description is not stored, so look it up
+        let plot = match chan.service {
+            Service::Youtube => {
+                let cid = crate::common::YoutubeID {
+                    id: chan.chanid.clone(),
+                };
+                match crate::youtube::YoutubeQuery::new(&cid).get_metadata() {
+                    Ok(meta) => meta.description,
+                    Err(e) => {
+                        warn!("Failed to get description for {:?} - {}", &chan, e);
+                        "".into()
+                    }
+                }
+            }
+            Service::Vimeo => "".into(),
+        };
+
+        let t = ShowInfoTemplate {
+            title: &chan.title,
+            plot: &plot,
+            service: chan.service.as_str(),
+            chanid: &chan.chanid,
+        };
+        std::fs::write(&nfo_path, t.render()?)
+            .with_context(|| format!("Failed to write {:?}", &nfo_path))?;
+        debug!("Wrote {:?}", &nfo_path);
+    }
+
+    let poster_path = dir.join(SHOW_POSTER);
+    if !poster_path.exists() && !chan.thumbnail.is_empty() {
+        let (_, data) = crate::thumbnails::download(&chan.thumbnail)?;
+        std::fs::write(&poster_path, data)
+            .with_context(|| format!("Failed to write {:?}", &poster_path))?;
+        debug!("Wrote {:?}", &poster_path);
+    }
+    Ok(())
+}
+
+/// Write `.nfo` file for a downloaded video, returning its path
+fn write_episode_info(
+    db: &Database,
+    media: &Path,
+    chan: &Channel,
+    video: &DBVideoInfo,
+) -> Result<PathBuf> {
+    let published = video.info.published_at;
+    let t = EpisodeInfoTemplate {
+        title: &video.info.title,
+        show: &chan.title,
+        season: published.year(),
+        // Videos are numbered in order of publishing within each season, so ones published on
+        // the same day are still distinct episodes
+        episode: video.number_in_year(db)?,
+        plot: &video.info.description,
+        aired: published.format("%Y-%m-%d").to_string(),
+        service: chan.service.as_str(),
+        id: &video.info.id,
+        thumb: &video.info.thumbnail_url,
+    };
+
+    let path = media.with_extension("nfo");
+    std::fs::write(&path, t.render()?).with_context(|| format!("Failed to write {:?}", &path))?;
+    Ok(path)
+}
+
+/// Create any extra files the layout requires for a newly downloaded video
+pub fn process(
+    cfg: &Config,
+    db: &Database,
+    chan: &Channel,
+    video: &DBVideoInfo,
+    media: &Path,
+) -> Result<()> {
+    match cfg.layout {
+        Layout::Flat => Ok(()),
+        Layout::MediaServer => {
+            if let Err(e) = write_show_info(cfg, chan) {
+                // Missing show info is not a reason to skip the episode info
+                warn!("Failed to write show info for {:?} - {:?}", &chan, e);
+            }
+            write_episode_info(db, media, chan, video)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sanitise_dirname() {
+        assert_eq!(sanitise_dirname("A channel"), "A channel");
+        assert_eq!(sanitise_dirname("AC/DC: Live?"), "AC_DC_ Live_");
+        assert_eq!(sanitise_dirname("..hidden. "), "hidden");
+    }
+}
@@ -2,18 +2,31 @@ extern crate serde;
 extern crate serde_json;
 
 use anyhow::Result;
-use log::{debug, error, info, warn};
+use log::{debug, info, warn};
 
 #[macro_use]
 extern crate serde_derive;
 
 use clap::{App, Arg, SubCommand};
 
+mod auth;
 mod backup;
 mod common;
 mod config;
 mod db;
 mod download;
+mod health;
+mod hooks;
+mod import;
+mod layout;
+mod metrics;
+mod notify;
+mod opml;
+mod scan;
+mod sidecar;
+mod takeout;
+mod thumbnails;
+mod users;
 mod web;
 mod worker;
 mod youtube;
@@ -23,14 +36,14 @@ use crate::common::{ChannelID, Service};
 fn update() -> Result<()> {
     // Load config
     debug!("Loading config");
-    let cfg = crate::config::Config::load();
+    let cfg = crate::config::Config::load()?;
     let db = crate::db::Database::open(&cfg)?;
 
     let work = worker::WorkerPool::start();
 
     // Get list of channels
     let channels = crate::db::list_channels(&db)?;
-    if channels.len() == 0 {
+    if channels.is_empty() {
         warn!("No channels yet added");
     }
 
@@ -53,10 +66,10 @@ fn add(name: &str, service_str: &str) -> Result<()> {
 
     match &cid {
         ChannelID::Youtube(ytid) => {
-            let yt = crate::youtube::YoutubeQuery::new(&ytid);
+            let yt = crate::youtube::YoutubeQuery::new(ytid);
 
             let meta = yt.get_metadata()?;
-            let cfg = crate::config::Config::load();
+            let cfg = crate::config::Config::load()?;
             let db = crate::db::Database::open(&cfg)?;
             info!("Adding Youtube channel {:?}", &ytid.id,);
             db::Channel::create(&db, &cid, &meta.title, &meta.thumbnail)?;
@@ -68,15 +81,15 @@ fn add(name: &str, service_str: &str) -> Result<()> {
 
 /// List videos
 fn list(chan_num: Option<&str>) -> Result<()> {
-    let cfg = crate::config::Config::load();
+    let cfg = crate::config::Config::load()?;
     let db = crate::db::Database::open(&cfg)?;
 
     if let Some(chan_num) = chan_num {
         // List specific channel
         let channels = crate::db::list_channels(&db)?;
         for c in channels {
-            if &format!("{}", c.id) == chan_num {
-                for v in c.all_videos(&db, 50, 0)? {
+            if format!("{}", c.id) == chan_num {
+                for v in c.all_videos(&db, &Default::default(), 50, 0)? {
                     let v = v.info;
                     println!(
                         "ID: {}\nTitle: {}\nURL: {}\nPublished: {}\nThumbnail: {}\nDescription: {}\n----",
@@ -102,6 +115,170 @@ fn list(chan_num: Option<&str>) -> Result<()> {
     Ok(())
 }
 
+/// Add credentials from the config to a request made to the web server
+fn with_credentials<B>(
+    cfg: &crate::config::Config,
+    req: attohttpc::RequestBuilder<B>,
+) -> attohttpc::RequestBuilder<B> {
+    if let Some(token) = cfg.web_auth.tokens.first() {
+        req.bearer_auth(token.as_str())
+    } else if let Some(ref username) = cfg.web_auth.username {
+        req.basic_auth(username, cfg.web_auth.password.as_ref())
+    } else {
+        req
+    }
+}
+
+/// Cancel a download. This is done through the web server if it is running, as it owns any
+/// active downloader processes
+fn cancel(videoid: i64, cleanup: bool) -> Result<()> {
+    let cfg = crate::config::Config::load()?;
+    let url = format!("{}/download/{}/cancel", cfg.local_web_url(), videoid);
+
+    // Requests with an API token are exempt from CSRF checks, otherwise a matching cookie and
+    // form field are made up as a browser would have been given
+    let csrf = crate::users::random_token()?;
+    let req = attohttpc::post(&url)
+        .header(
+            attohttpc::header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded",
+        )
+        .header(
+            attohttpc::header::COOKIE,
+            format!("{}={}", crate::auth::CSRF_COOKIE, csrf),
+        );
+    let req = with_credentials(&cfg, req);
+    let body = format!(
+        "cleanup={}&{}={}",
+        if cleanup { 1 } else { 0 },
+        crate::auth::CSRF_FIELD,
+        csrf
+    );
+
+    // Redirects are not followed, as that means a login is needed rather than success
+    match req.follow_redirects(false).text(body).send() {
+        Ok(resp) if resp.is_success() => Ok(()),
+        Ok(resp) => Err(anyhow::anyhow!(
+            "Web server failed to cancel download: {}",
+            resp.text()?
+        )),
+        Err(e) => {
+            debug!("Web server not reachable ({}), cancelling in database", e);
+            let db = crate::db::Database::open(&cfg)?;
+            let v = crate::db::DBVideoInfo::get_by_sqlid(&db, videoid)?;
+            crate::download::cancel(&db, &cfg, &v, cleanup)
+        }
+    }
+}
+
+/// Show what a running web server's workers are doing. If it is not running, show outstanding
+/// and failed downloads from the database instead
+fn status() -> Result<()> {
+    let cfg = crate::config::Config::load()?;
+    let url = format!("{}/queue.json", cfg.local_web_url());
+    let resp = with_credentials(&cfg, attohttpc::get(&url))
+        .follow_redirects(false)
+        .send();
+    let resp = match resp {
+        Ok(resp) if resp.is_success() => resp,
+        Ok(resp) => {
+            return Err(anyhow::anyhow!(
+                "Web server failed to report status: {}",
+                resp.text()?
+            ))
+        }
+        Err(e) => {
+            debug!("Web server not reachable ({}), reading database", e);
+            println!("Web server not running");
+            let db = crate::db::Database::open(&cfg)?;
+            for (label, status) in &[
+                ("Downloading", crate::common::VideoStatus::Downloading),
+                ("Queued", crate::common::VideoStatus::Queued),
+                ("Failed", crate::common::VideoStatus::GrabError),
+            ] {
+                let filter = crate::db::VideoFilter {
+                    statuses: vec![*status],
+                    ..Default::default()
+                };
+                let videos = crate::db::all_videos(&db, &filter, 50, 0)?;
+                println!("{} ({}):", label, videos.len());
+                for v in videos {
+                    match v.last_error {
+                        Some(ref e) if *status == crate::common::VideoStatus::GrabError => {
+                            println!("  {} - {} - {}", v.id, v.info.title, e)
+                        }
+                        _ => println!("  {} - {}", v.id, v.info.title),
+                    }
+                }
+            }
+            return Ok(());
+        }
+    };
+
+    let queue: crate::worker::QueueReport = serde_json::from_slice(&resp.bytes()?)?;
+    println!("Running ({}):", queue.running.len());
+    for j in &queue.running {
+        println!(
+            "  worker {} - {} - {}s",
+            j.worker.unwrap_or_default(),
+            j.description,
+            j.run_secs.unwrap_or_default()
+        );
+    }
+    println!("Pending ({}):", queue.pending.len());
+    for j in &queue.pending {
+        println!("  {} - waiting {}s", j.description, j.wait_secs);
+    }
+    println!("Recently finished:");
+    for j in queue.finished.iter().take(10) {
+        match j.error {
+            Some(ref e) => println!("  {} - failed: {}", j.description, e),
+            None => println!(
+                "  {} - took {}s",
+                j.description,
+                j.run_secs.unwrap_or_default()
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Show or set the quality profile used for a channel's downloads
+fn profile(chan_num: &str, name: Option<&str>, clear: bool) -> Result<()> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let chan = crate::db::Channel::get_by_sqlid(&db, chan_num.parse()?)?;
+
+    if clear {
+        chan.set_profile(&db, None)?;
+    } else if let Some(name) = name {
+        cfg.profile(name)?;
+        chan.set_profile(&db, Some(name))?;
+    } else {
+        println!(
+            "{} - profile {}",
+            chan.title,
+            chan.profile
+                .unwrap_or_else(|| format!("{} (default)", cfg.default_profile))
+        );
+        println!("Available profiles:");
+        for (name, p) in &cfg.profiles {
+            println!("  {} - {}", name, p.downloader_args().join(" "));
+        }
+    }
+    Ok(())
+}
+
+/// Delete a video's downloaded file and sidecars, returning it to new
+fn delete(videoid: &str) -> Result<()> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let v = crate::db::DBVideoInfo::get_by_sqlid(&db, videoid.parse()?)?;
+    crate::sidecar::delete_files(&db, &v)?;
+    v.set_status(&db, crate::common::VideoStatus::New)?;
+    Ok(())
+}
+
 fn config_logging(verbosity: u64) -> Result<()> {
     // Level for this application
     let internal_level = match verbosity {
@@ -170,15 +347,115 @@ fn main() -> Result<()> {
                 .short("o")
                 .long("output")
                 .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("include-credentials")
+                .long("include-credentials")
+                .help("include users' password hashes, which allow guessing passwords offline"),
         );
     let sc_backup = SubCommand::with_name("backup")
         .about("Backup database as simple .json file")
         .subcommand(sc_import)
         .subcommand(sc_export);
 
+    // Subscription import/export subcommands
+    let sc_subs_import = SubCommand::with_name("import")
+        .about("add channels from another application's subscriptions")
+        .subcommand(
+            SubCommand::with_name("opml")
+                .about("import channels from an OPML file")
+                .arg(Arg::with_name("file").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("takeout")
+                .about("import subscriptions.csv or .json from a Google Takeout export")
+                .arg(Arg::with_name("file").required(true))
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("only show which channels would be added"),
+                ),
+        );
+    let sc_subs_export = SubCommand::with_name("export")
+        .about("export channels for another application")
+        .subcommand(
+            SubCommand::with_name("opml")
+                .about("export channels as an OPML file")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true),
+                ),
+        );
+
     // Download subcommand
     let sc_download = SubCommand::with_name("download").about("enqueues videos for download");
 
+    // Scan subcommand
+    let sc_scan = SubCommand::with_name("scan")
+        .about("reconcile database with files in the download directory")
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("only report changes, without modifying the database"),
+        );
+
+    // Cancel subcommand
+    let sc_cancel = SubCommand::with_name("cancel")
+        .about("cancel queued or in-progress download")
+        .arg(Arg::with_name("id").required(true))
+        .arg(
+            Arg::with_name("cleanup")
+                .long("cleanup")
+                .help("remove partially downloaded files"),
+        );
+
+    // Profile subcommand
+    let sc_profile = SubCommand::with_name("profile")
+        .about("show or set quality profile used for a channel")
+        .arg(Arg::with_name("chanid").required(true))
+        .arg(Arg::with_name("name"))
+        .arg(
+            Arg::with_name("clear")
+                .long("clear")
+                .conflicts_with("name")
+                .help("use the default profile"),
+        );
+
+    // Delete subcommand
+    let sc_delete = SubCommand::with_name("delete")
+        .about("delete downloaded file and sidecars for video")
+        .arg(Arg::with_name("id").required(true));
+
+    // User subcommands
+    let sc_user = SubCommand::with_name("user")
+        .about("manage web interface accounts")
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("create user, reading password from stdin")
+                .arg(Arg::with_name("name").required(true))
+                .arg(
+                    Arg::with_name("admin")
+                        .long("admin")
+                        .help("allow managing users and configuration"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("passwd")
+                .about("change password, reading it from stdin")
+                .arg(Arg::with_name("name").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("delete user")
+                .arg(Arg::with_name("name").required(true)),
+        )
+        .subcommand(SubCommand::with_name("list").about("list users"));
+
+    // Status subcommand
+    let sc_status = SubCommand::with_name("status").about("show queued and running work");
+
     // Download subcommand
     let sc_worker = SubCommand::with_name("worker").about("download worker thread test");
 
@@ -189,8 +466,16 @@ fn main() -> Result<()> {
         .subcommand(sc_list)
         .subcommand(sc_web)
         .subcommand(sc_backup)
+        .subcommand(sc_subs_import)
+        .subcommand(sc_subs_export)
         .subcommand(sc_download)
         .subcommand(sc_worker)
+        .subcommand(sc_scan)
+        .subcommand(sc_cancel)
+        .subcommand(sc_profile)
+        .subcommand(sc_delete)
+        .subcommand(sc_user)
+        .subcommand(sc_status)
         .arg(
             Arg::with_name("verbose")
                 .short("v")
@@ -219,11 +504,59 @@ fn main() -> Result<()> {
         ("list", Some(sub_m)) => list(sub_m.value_of("id"))?,
         ("web", Some(_sub_m)) => crate::web::main()?,
         ("backup", Some(sub_m)) => match sub_m.subcommand() {
-            ("export", Some(sub_m)) => crate::backup::export(sub_m.value_of("output"))?,
+            ("export", Some(sub_m)) => crate::backup::export(
+                sub_m.value_of("output"),
+                sub_m.is_present("include-credentials"),
+            )?,
             ("import", Some(_sub_m)) => crate::backup::import()?,
             _ => return Err(anyhow::anyhow!("Unhandled backup subcommand")),
         },
+        ("import", Some(sub_m)) => match sub_m.subcommand() {
+            ("opml", Some(sub_m)) => crate::opml::import_file(
+                sub_m.value_of("file").expect("required arg file missing"),
+            )?,
+            ("takeout", Some(sub_m)) => crate::takeout::import_file(
+                sub_m.value_of("file").expect("required arg file missing"),
+                sub_m.is_present("dry-run"),
+            )?,
+            _ => return Err(anyhow::anyhow!("Unhandled import subcommand")),
+        },
+        ("export", Some(sub_m)) => match sub_m.subcommand() {
+            ("opml", Some(sub_m)) => crate::opml::export_file(sub_m.value_of("output"))?,
+            _ => return Err(anyhow::anyhow!("Unhandled export subcommand")),
+        },
         ("worker", Some(_sub_m)) => crate::worker::main()?,
+        ("scan", Some(sub_m)) => crate::scan::scan(sub_m.is_present("dry-run"))?,
+        ("cancel", Some(sub_m)) => {
+            let id: i64 = sub_m
+                .value_of("id")
+                .expect("required arg id missing")
+                .parse()?;
+            cancel(id, sub_m.is_present("cleanup"))?
+        }
+        ("status", Some(_sub_m)) => status()?,
+        ("delete", Some(sub_m)) => delete(sub_m.value_of("id").expect("required arg id missing"))?,
+        ("profile", Some(sub_m)) => profile(
+            sub_m
+                .value_of("chanid")
+                .expect("required arg chanid missing"),
+            sub_m.value_of("name"),
+            sub_m.is_present("clear"),
+        )?,
+        ("user", Some(sub_m)) => match sub_m.subcommand() {
+            ("add", Some(sub_m)) => crate::users::add(
+                sub_m.value_of("name").expect("required arg name missing"),
+                sub_m.is_present("admin"),
+            )?,
+            ("passwd", Some(sub_m)) => {
+                crate::users::passwd(sub_m.value_of("name").expect("required arg name missing"))?
+            }
+            ("delete", Some(sub_m)) => {
+                crate::users::remove(sub_m.value_of("name").expect("required arg name missing"))?
+            }
+            ("list", Some(_sub_m)) => crate::users::list()?,
+            _ => return Err(anyhow::anyhow!("Unhandled user subcommand")),
+        },
         _ => {
             return Err(anyhow::anyhow!("Unhandled subcommand"));
         }
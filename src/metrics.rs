@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+
+use crate::common::{lock, VideoStatus};
+use crate::db::Database;
+use crate::worker::QueueReport;
+
+/// Count and total of observed values, exposed as a Prometheus summary without quantiles
+#[derive(Default, Debug, Clone, Copy)]
+struct Summary {
+    count: u64,
+    sum: f64,
+}
+
+impl Summary {
+    fn observe(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Counters updated as work happens. Everything else is read from the database or worker pool
+/// when metrics are requested
+#[derive(Default, Debug)]
+struct Counters {
+    /// Update check durations by channel SQL ID
+    update_checks: BTreeMap<i64, Summary>,
+    update_failures: BTreeMap<i64, u64>,
+    /// Durations of successful downloads
+    downloads: Summary,
+    download_failures: u64,
+    download_bytes: u64,
+    thumbnail_hits: u64,
+    thumbnail_misses: u64,
+}
+
+lazy_static! {
+    static ref COUNTERS: Mutex<Counters> = Mutex::new(Counters::default());
+}
+
+pub fn update_checked(chanid: i64, duration: Duration, ok: bool) {
+    let mut c = lock(&COUNTERS);
+    c.update_checks
+        .entry(chanid)
+        .or_default()
+        .observe(duration.as_secs_f64());
+    if !ok {
+        *c.update_failures.entry(chanid).or_default() += 1;
+    }
+}
+
+/// Record a finished download, with `bytes` being `None` if it failed
+pub fn download_finished(duration: Duration, bytes: Option<u64>) {
+    let mut c = lock(&COUNTERS);
+    match bytes {
+        Some(b) => {
+            c.downloads.observe(duration.as_secs_f64());
+            c.download_bytes += b;
+        }
+        None => c.download_failures += 1,
+    }
+}
+
+pub fn thumbnail_lookup(hit: bool) {
+    let mut c = lock(&COUNTERS);
+    if hit {
+        c.thumbnail_hits += 1;
+    } else {
+        c.thumbnail_misses += 1;
+    }
+}
+
+/// Write `# HELP` and `# TYPE` lines introducing a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+impl Counters {
+    fn render(&self, out: &mut String) {
+        header(
+            out,
+            "vidl_update_check_duration_seconds",
+            "summary",
+            "Time taken checking channels for new videos",
+        );
+        for (chan, s) in &self.update_checks {
+            writeln!(
+                out,
+                "vidl_update_check_duration_seconds_sum{{channel=\"{}\"}} {}",
+                chan, s.sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "vidl_update_check_duration_seconds_count{{channel=\"{}\"}} {}",
+                chan, s.count
+            )
+            .unwrap();
+        }
+        header(
+            out,
+            "vidl_update_check_failures_total",
+            "counter",
+            "Channel update checks which failed",
+        );
+        for (chan, n) in &self.update_failures {
+            writeln!(
+                out,
+                "vidl_update_check_failures_total{{channel=\"{}\"}} {}",
+                chan, n
+            )
+            .unwrap();
+        }
+
+        header(
+            out,
+            "vidl_download_duration_seconds",
+            "summary",
+            "Time taken by successful downloads, including retries",
+        );
+        writeln!(
+            out,
+            "vidl_download_duration_seconds_sum {}",
+            self.downloads.sum
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "vidl_download_duration_seconds_count {}",
+            self.downloads.count
+        )
+        .unwrap();
+        header(
+            out,
+            "vidl_download_failures_total",
+            "counter",
+            "Downloads which failed after all retries",
+        );
+        writeln!(
+            out,
+            "vidl_download_failures_total {}",
+            self.download_failures
+        )
+        .unwrap();
+        header(
+            out,
+            "vidl_download_bytes_total",
+            "counter",
+            "Size of downloaded files",
+        );
+        writeln!(out, "vidl_download_bytes_total {}", self.download_bytes).unwrap();
+
+        header(
+            out,
+            "vidl_thumbnail_cache_requests_total",
+            "counter",
+            "Thumbnail requests, by whether the image was cached",
+        );
+        writeln!(
+            out,
+            "vidl_thumbnail_cache_requests_total{{result=\"hit\"}} {}",
+            self.thumbnail_hits
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "vidl_thumbnail_cache_requests_total{{result=\"miss\"}} {}",
+            self.thumbnail_misses
+        )
+        .unwrap();
+    }
+}
+
+/// All metrics in the Prometheus text exposition format
+pub fn render(db: &Database, queue: &QueueReport) -> Result<String> {
+    let mut out = String::new();
+
+    header(&mut out, "vidl_videos", "gauge", "Videos by status");
+    let counts = crate::db::status_counts(db)?;
+    for status in &[
+        VideoStatus::New,
+        VideoStatus::Queued,
+        VideoStatus::Downloading,
+        VideoStatus::Grabbed,
+        VideoStatus::GrabError,
+        VideoStatus::Ignore,
+    ] {
+        let n = counts
+            .iter()
+            .find(|(s, _)| s == status)
+            .map(|(_, n)| *n)
+            .unwrap_or(0);
+        let name = format!("{:?}", status).to_lowercase();
+        writeln!(out, "vidl_videos{{status=\"{}\"}} {}", name, n)?;
+    }
+
+    header(&mut out, "vidl_channels", "gauge", "Channels added");
+    writeln!(out, "vidl_channels {}", crate::db::list_channels(db)?.len())?;
+
+    header(
+        &mut out,
+        "vidl_queue_depth",
+        "gauge",
+        "Work items waiting for a worker, by type",
+    );
+    for kind in &["download", "update-check", "thumbnail"] {
+        let n = queue.pending.iter().filter(|j| j.kind == *kind).count();
+        writeln!(out, "vidl_queue_depth{{kind=\"{}\"}} {}", kind, n)?;
+    }
+
+    lock(&COUNTERS).render(&mut out);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_counters() {
+        let mut c = Counters::default();
+        c.update_checks.entry(3).or_default().observe(1.5);
+        c.update_checks.entry(3).or_default().observe(0.5);
+        c.update_failures.insert(3, 1);
+        c.downloads.observe(10.0);
+        c.download_bytes = 1234;
+        c.thumbnail_hits = 7;
+
+        let mut out = String::new();
+        c.render(&mut out);
+        assert!(out.contains("# TYPE vidl_update_check_duration_seconds summary\n"));
+        assert!(out.contains("vidl_update_check_duration_seconds_sum{channel=\"3\"} 2\n"));
+        assert!(out.contains("vidl_update_check_duration_seconds_count{channel=\"3\"} 2\n"));
+        assert!(out.contains("vidl_update_check_failures_total{channel=\"3\"} 1\n"));
+        assert!(out.contains("vidl_download_duration_seconds_count 1\n"));
+        assert!(out.contains("vidl_download_bytes_total 1234\n"));
+        assert!(out.contains("vidl_thumbnail_cache_requests_total{result=\"hit\"} 7\n"));
+        assert!(out.contains("vidl_thumbnail_cache_requests_total{result=\"miss\"} 0\n"));
+    }
+}
@@ -0,0 +1,345 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{debug, error};
+
+use crate::config::Config;
+use crate::db::{Channel, DBVideoInfo};
+
+/// How long to wait for a notification sink before giving up
+const SINK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Type of event a notification is sent for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    ServerStarted,
+    NewVideos,
+    Grabbed,
+    Failed,
+}
+
+/// Something which happened that users may want to be told about
+#[derive(Serialize, Debug, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    /// Short one-line description
+    pub summary: String,
+    /// Longer description, may contain multiple lines
+    pub body: String,
+    /// Link to more information, e.g the video or web interface
+    pub url: Option<String>,
+}
+
+impl Event {
+    pub fn new_videos(chan: &Channel, videos: &[DBVideoInfo]) -> Event {
+        let titles: Vec<&str> = videos.iter().map(|v| v.info.title.as_str()).collect();
+        Event {
+            kind: EventKind::NewVideos,
+            summary: format!("{} new videos from {}", videos.len(), chan.title),
+            body: titles.join("\n"),
+            url: None,
+        }
+    }
+
+    pub fn grabbed(chan: &Channel, video: &DBVideoInfo) -> Event {
+        Event {
+            kind: EventKind::Grabbed,
+            summary: format!("Grabbed {}", video.info.title),
+            body: format!("Downloaded {} from {}", video.info.title, chan.title),
+            url: Some(video.info.url.clone()),
+        }
+    }
+
+    pub fn failed(chan: &Channel, video: &DBVideoInfo, error: &str) -> Event {
+        Event {
+            kind: EventKind::Failed,
+            summary: format!("Failed to grab {}", video.info.title),
+            body: format!(
+                "Error downloading {} from {}: {}",
+                video.info.title, chan.title, error
+            ),
+            url: Some(video.info.url.clone()),
+        }
+    }
+}
+
+/// Destination notifications are delivered to
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Sink {
+    /// POST the event as JSON to a URL
+    Webhook { url: String },
+    /// Send a plain text email via an SMTP relay. No authentication or TLS is supported, so
+    /// this is intended for a relay on the local machine or network
+    Email {
+        /// Host and port of SMTP server, e.g `localhost:25`
+        server: String,
+        from: String,
+        to: Vec<String>,
+    },
+    /// Desktop notification, using `notify-send` on Linux or `terminal-notifier` on macOS
+    Desktop,
+}
+
+/// Configured notification sink, along with which events are sent to it
+#[derive(Deserialize, Debug, Clone)]
+pub struct Notifier {
+    #[serde(flatten)]
+    pub sink: Sink,
+    /// Events to send, all events if not specified
+    pub events: Option<Vec<EventKind>>,
+}
+
+impl Notifier {
+    fn wants(&self, kind: EventKind) -> bool {
+        match self.events {
+            Some(ref events) => events.contains(&kind),
+            None => true,
+        }
+    }
+}
+
+impl Sink {
+    /// Deliver event to this sink
+    pub fn send(&self, event: &Event) -> Result<()> {
+        match self {
+            Sink::Webhook { url } => send_webhook(url, event),
+            Sink::Email { server, from, to } => send_email(server, from, to, event),
+            Sink::Desktop => send_desktop(event),
+        }
+    }
+}
+
+fn send_webhook(url: &str, event: &Event) -> Result<()> {
+    let resp = attohttpc::post(url)
+        .timeout(SINK_TIMEOUT)
+        .header(attohttpc::header::CONTENT_TYPE, "application/json")
+        .bytes(serde_json::to_vec(event)?)
+        .send()
+        .with_context(|| format!("Failed to send webhook to {}", url))?;
+    if !resp.status().is_success() {
+        return Err(anyhow::anyhow!(
+            "Webhook {} responded with {}",
+            url,
+            resp.status()
+        ));
+    }
+    Ok(())
+}
+
+/// Minimal SMTP client conversation over an established connection
+struct SmtpSession<'a> {
+    reader: BufReader<&'a TcpStream>,
+    writer: &'a TcpStream,
+}
+
+impl<'a> SmtpSession<'a> {
+    /// Read a (possibly multi-line) reply, checking its code has the expected first digit
+    fn expect(&mut self, class: char) -> Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(anyhow::anyhow!("SMTP server closed connection"));
+            }
+            if !line.starts_with(class) {
+                return Err(anyhow::anyhow!(
+                    "Unexpected SMTP reply {:?}",
+                    line.trim_end()
+                ));
+            }
+            // Continuation lines are of the form "250-..."
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    fn command(&mut self, cmd: &str, class: char) -> Result<()> {
+        write!(self.writer, "{}\r\n", cmd)?;
+        self.expect(class)
+    }
+}
+
+/// Build message headers and body, with lines starting with `.` escaped as required by `DATA`
+fn email_message(from: &str, to: &[String], event: &Event) -> String {
+    let mut body = event.body.clone();
+    if let Some(ref url) = event.url {
+        body.push_str("\n\n");
+        body.push_str(url);
+    }
+    let body: Vec<String> = body
+        .lines()
+        .map(|l| {
+            if l.starts_with('.') {
+                format!(".{}", l)
+            } else {
+                l.to_string()
+            }
+        })
+        .collect();
+
+    format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+        from,
+        to.join(", "),
+        event.summary.replace(['\r', '\n'], " "),
+        chrono::Utc::now().to_rfc2822(),
+        body.join("\r\n"),
+    )
+}
+
+fn send_email(server: &str, from: &str, to: &[String], event: &Event) -> Result<()> {
+    let stream = TcpStream::connect(server)
+        .with_context(|| format!("Failed to connect to SMTP server {}", server))?;
+    stream.set_read_timeout(Some(SINK_TIMEOUT))?;
+    stream.set_write_timeout(Some(SINK_TIMEOUT))?;
+
+    let mut smtp = SmtpSession {
+        reader: BufReader::new(&stream),
+        writer: &stream,
+    };
+    smtp.expect('2')?;
+    smtp.command("EHLO vidl", '2')?;
+    smtp.command(&format!("MAIL FROM:<{}>", from), '2')?;
+    for addr in to {
+        smtp.command(&format!("RCPT TO:<{}>", addr), '2')?;
+    }
+    smtp.command("DATA", '3')?;
+    write!(smtp.writer, "{}", email_message(from, to, event))?;
+    smtp.command(".", '2')?;
+    smtp.command("QUIT", '2')?;
+    Ok(())
+}
+
+fn send_desktop(event: &Event) -> Result<()> {
+    let mut cmd = if cfg!(target_os = "macos") {
+        let mut cmd = Command::new("terminal-notifier");
+        cmd.args(["-title", &event.summary, "-message", &event.body]);
+        if let Some(ref url) = event.url {
+            cmd.args(["-open", url]);
+        }
+        cmd
+    } else {
+        let mut cmd = Command::new("notify-send");
+        cmd.args(["--app-name", "vidl", &event.summary, &event.body]);
+        cmd
+    };
+    // Not waited for, as notification programs can block until dismissed
+    cmd.spawn().context("Failed to run desktop notifier")?;
+    Ok(())
+}
+
+/// Send event to all notifiers interested in it. Failures are logged rather than returned, so
+/// a broken sink never interrupts the caller
+pub fn notify(cfg: &Config, event: &Event) {
+    for n in cfg.notifiers.iter().filter(|n| n.wants(event.kind)) {
+        debug!("Sending {:?} to {:?}", &event.summary, &n.sink);
+        if let Err(e) = n.sink.send(event) {
+            error!("Failed to send notification {:?} - {:?}", &event.summary, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn event() -> Event {
+        Event {
+            kind: EventKind::Grabbed,
+            summary: "Grabbed A video".into(),
+            body: "First line\n.dotted line".into(),
+            url: Some("http://localhost:8448/video/1/play".into()),
+        }
+    }
+
+    #[test]
+    fn test_webhook() -> Result<()> {
+        let m = mockito::mock("POST", "/hook")
+            .match_header("content-type", "application/json")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "kind": "grabbed",
+                "summary": "Grabbed A video",
+            })))
+            .with_status(204)
+            .create();
+
+        let sink = Sink::Webhook {
+            url: format!("{}/hook", mockito::server_url()),
+        };
+        sink.send(&event())?;
+        m.assert();
+        Ok(())
+    }
+
+    #[test]
+    fn test_email() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        // Stand-in SMTP server accepting a single message, returning everything it received
+        let server = std::thread::spawn(move || -> String {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut writer = &stream;
+            let mut received = String::new();
+            writer.write_all(b"220 localhost ready\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push_str(&line);
+                let reply: &[u8] = if line.starts_with("EHLO") {
+                    b"250-localhost\r\n250 8BITMIME\r\n"
+                } else if line.starts_with("DATA") {
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    b"221 bye\r\n"
+                } else if line == ".\r\n" || line.starts_with("MAIL") || line.starts_with("RCPT") {
+                    b"250 ok\r\n"
+                } else {
+                    // Message content, no reply until the terminating "."
+                    continue;
+                };
+                writer.write_all(reply).unwrap();
+                if line.starts_with("QUIT") {
+                    break;
+                }
+            }
+            received
+        });
+
+        let sink = Sink::Email {
+            server: addr.to_string(),
+            from: "vidl@localhost".into(),
+            to: vec!["user@example.com".into()],
+        };
+        sink.send(&event())?;
+
+        let received = server.join().unwrap();
+        assert!(received.contains("MAIL FROM:<vidl@localhost>\r\n"));
+        assert!(received.contains("RCPT TO:<user@example.com>\r\n"));
+        assert!(received.contains("Subject: Grabbed A video\r\n"));
+        assert!(received.contains("First line\r\n..dotted line\r\n"));
+        assert!(received.contains("http://localhost:8448/video/1/play"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_notifier_events() {
+        let n: Notifier =
+            serde_json::from_str(r#"{"type": "webhook", "url": "http://x", "events": ["failed"]}"#)
+                .unwrap();
+        assert!(n.wants(EventKind::Failed));
+        assert!(!n.wants(EventKind::Grabbed));
+
+        let n: Notifier = serde_json::from_str(r#"{"type": "desktop"}"#).unwrap();
+        assert!(n.wants(EventKind::NewVideos));
+    }
+}
@@ -0,0 +1,347 @@
+use anyhow::{Context, Result};
+use askama::Template;
+
+use crate::common::Service;
+use crate::config::Config;
+use crate::db::{Channel, Database};
+use crate::import::{add_channel, ImportReport};
+
+/// Feed entry from an OPML file
+#[derive(Debug, PartialEq)]
+pub struct Outline {
+    pub title: Option<String>,
+    pub xml_url: Option<String>,
+    pub html_url: Option<String>,
+}
+
+impl Outline {
+    /// How the entry is described when reporting it
+    pub fn label(&self) -> &str {
+        self.title
+            .as_deref()
+            .or(self.xml_url.as_deref())
+            .or(self.html_url.as_deref())
+            .unwrap_or("(untitled)")
+    }
+}
+
+/// Replace XML entities in an attribute value
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16)
+                .ok()
+                .and_then(std::char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        };
+        match c {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Parse `name="value"` pairs from the inside of a tag, returning them along with the length
+/// of the tag up to and including its closing `>`
+fn attributes(tag: &str) -> Result<(Vec<(&str, String)>, usize)> {
+    let mut attrs = vec![];
+    let mut pos = 0;
+    loop {
+        let rest = &tag[pos..];
+        let trimmed = rest.trim_start();
+        pos += rest.len() - trimmed.len();
+        if trimmed.starts_with('>') {
+            return Ok((attrs, pos + 1));
+        } else if trimmed.starts_with("/>") {
+            return Ok((attrs, pos + 2));
+        }
+
+        let eq = trimmed
+            .find('=')
+            .ok_or_else(|| anyhow::anyhow!("Malformed attribute in {:?}", trimmed))?;
+        let name = trimmed[..eq].trim();
+        let value = trimmed[eq + 1..].trim_start();
+        let quote = value
+            .chars()
+            .next()
+            .filter(|&c| c == '"' || c == '\'')
+            .ok_or_else(|| anyhow::anyhow!("Unquoted value for attribute {:?}", name))?;
+        let len = value[1..]
+            .find(quote)
+            .ok_or_else(|| anyhow::anyhow!("Unterminated value for attribute {:?}", name))?;
+        attrs.push((name, unescape(&value[1..1 + len])));
+        pos += trimmed.len() - value.len() + len + 2;
+    }
+}
+
+/// Find the feed entries in an OPML document. Folders (outlines without a URL) are skipped,
+/// but the feeds inside them are included
+pub fn parse(data: &str) -> Result<Vec<Outline>> {
+    if !data.contains("<opml") {
+        return Err(anyhow::anyhow!("Not an OPML document"));
+    }
+
+    let mut outlines = vec![];
+    let mut rest = data;
+    while let Some(start) = rest.find("<outline") {
+        rest = &rest[start + "<outline".len()..];
+        // Skip other elements which merely start with "outline"
+        if !rest.starts_with(|c: char| c.is_whitespace() || c == '/' || c == '>') {
+            continue;
+        }
+        let (attrs, len) = attributes(rest)?;
+        rest = &rest[len..];
+
+        let get = |name: &str| {
+            attrs
+                .iter()
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.clone())
+        };
+        let o = Outline {
+            title: get("title").or_else(|| get("text")),
+            xml_url: get("xmlUrl"),
+            html_url: get("htmlUrl"),
+        };
+        if o.xml_url.is_some() || o.html_url.is_some() {
+            outlines.push(o);
+        }
+    }
+    Ok(outlines)
+}
+
+/// Channel ID or username from a YouTube URL, such as a channel's feed
+/// (`https://www.youtube.com/feeds/videos.xml?channel_id=UC...`) or page
+/// (`https://www.youtube.com/channel/UC...`, `/user/name`, `/c/name`)
+pub fn channel_name(url: &str) -> Option<String> {
+    let without_scheme = url.splitn(2, "://").last()?;
+    let (host, path) = match without_scheme.find('/') {
+        Some(i) => without_scheme.split_at(i),
+        None => return None,
+    };
+    if host != "youtube.com" && !host.ends_with(".youtube.com") {
+        return None;
+    }
+    let (path, query) = match path.find('?') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => (path, ""),
+    };
+
+    let from_query = query
+        .split('&')
+        .filter_map(|kv| {
+            let mut kv = kv.splitn(2, '=');
+            Some((kv.next()?, kv.next()?))
+        })
+        .find(|(k, v)| *k == "channel_id" || (*k == "user" && !v.is_empty()))
+        .map(|(_, v)| v.to_string());
+    if from_query.is_some() {
+        return from_query;
+    }
+
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    match (segments.next(), segments.next()) {
+        (Some("channel"), Some(name)) | (Some("user"), Some(name)) | (Some("c"), Some(name)) => {
+            Some(name.into())
+        }
+        _ => None,
+    }
+}
+
+/// Add the channel for an outline, returning its title and whether it was newly added
+fn add_outline(db: &Database, o: &Outline) -> Result<(String, bool)> {
+    let name = o
+        .xml_url
+        .as_deref()
+        .and_then(channel_name)
+        .or_else(|| o.html_url.as_deref().and_then(channel_name))
+        .ok_or_else(|| anyhow::anyhow!("Not a YouTube channel URL"))?;
+
+    // Avoid looking up channels which are already added by ID
+    if let Ok(c) = Channel::get(db, &Service::Youtube.get_channel_id(&name)) {
+        return Ok((c.title, false));
+    }
+    let cid = crate::youtube::find_channel_id(&name, &Service::Youtube)
+        .with_context(|| format!("Failed to find channel {:?}", name))?;
+    add_channel(db, &cid, None, false)
+}
+
+/// Add a channel for each feed in an OPML document
+pub fn import(db: &Database, data: &str) -> Result<ImportReport> {
+    let mut report = ImportReport::new(false);
+    for o in parse(data)? {
+        report.record(o.label(), add_outline(db, &o));
+    }
+    Ok(report)
+}
+
+struct OpmlChannel {
+    title: String,
+    xml_url: String,
+    html_url: String,
+}
+
+#[derive(Template)]
+#[template(path = "opml.xml")]
+struct OpmlTemplate<'a> {
+    date_created: String,
+    channels: &'a [OpmlChannel],
+}
+
+/// OPML document listing the feed of each YouTube channel
+pub fn render(channels: &[Channel]) -> Result<String> {
+    let channels: Vec<OpmlChannel> = channels
+        .iter()
+        .filter(|c| c.service == Service::Youtube)
+        .map(|c| OpmlChannel {
+            title: c.title.clone(),
+            xml_url: format!(
+                "https://www.youtube.com/feeds/videos.xml?channel_id={}",
+                c.chanid
+            ),
+            html_url: format!("https://www.youtube.com/channel/{}", c.chanid),
+        })
+        .collect();
+    let t = OpmlTemplate {
+        date_created: chrono::Utc::now().to_rfc2822(),
+        channels: &channels,
+    };
+    Ok(t.render()?)
+}
+
+/// Import channels from an OPML file, printing any which could not be added
+pub fn import_file(path: &str) -> Result<()> {
+    let cfg = Config::load()?;
+    let db = Database::open(&cfg)?;
+
+    let data = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    let report = import(&db, &data)?;
+    println!("{}", report.summary());
+    Ok(())
+}
+
+/// Write channels as OPML to a file, or stdout
+pub fn export_file(output: Option<&str>) -> Result<()> {
+    let cfg = Config::load()?;
+    let db = Database::open(&cfg)?;
+
+    let data = render(&crate::db::list_channels(&db)?)?;
+    match output {
+        Some(output) => std::fs::write(output, data)?,
+        None => print!("{}", data),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let data = r#"<?xml version="1.0"?>
+<opml version="1.1">
+<body>
+  <outline text="Folder">
+    <outline text="Tom &amp; Jerry" type="rss"
+        xmlUrl="https://www.youtube.com/feeds/videos.xml?channel_id=UCabc" />
+    <outline title='Blog' xmlUrl='https://example.com/feed&#x2f;' htmlUrl="https://example.com"/>
+  </outline>
+  <outlines-extension />
+</body>
+</opml>"#;
+        let outlines = parse(data)?;
+        assert_eq!(
+            outlines,
+            vec![
+                Outline {
+                    title: Some("Tom & Jerry".into()),
+                    xml_url: Some(
+                        "https://www.youtube.com/feeds/videos.xml?channel_id=UCabc".into()
+                    ),
+                    html_url: None,
+                },
+                Outline {
+                    title: Some("Blog".into()),
+                    xml_url: Some("https://example.com/feed/".into()),
+                    html_url: Some("https://example.com".into()),
+                },
+            ]
+        );
+        assert!(parse("<html></html>").is_err());
+        assert!(parse("<opml><outline xmlUrl=\"x></opml>").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_channel_name() {
+        let name = |url| channel_name(url);
+        assert_eq!(
+            name("https://www.youtube.com/feeds/videos.xml?channel_id=UCabc"),
+            Some("UCabc".into())
+        );
+        assert_eq!(
+            name("http://youtube.com/feeds/videos.xml?user=thegreatsd"),
+            Some("thegreatsd".into())
+        );
+        assert_eq!(
+            name("https://m.youtube.com/channel/UCabc/videos"),
+            Some("UCabc".into())
+        );
+        assert_eq!(
+            name("https://www.youtube.com/c/climb"),
+            Some("climb".into())
+        );
+        assert_eq!(name("https://www.youtube.com/watch?v=123"), None);
+        assert_eq!(name("https://notyoutube.com/channel/UCabc"), None);
+        assert_eq!(name("https://example.com/feed"), None);
+    }
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let db = Database::open_in_memory()?;
+        let cid = Service::Youtube.get_channel_id("UCabc");
+        Channel::create(&db, &cid, "Tom & Jerry", "")?;
+
+        let data = render(&crate::db::list_channels(&db)?)?;
+        let outlines = parse(&data)?;
+        assert_eq!(outlines.len(), 1);
+        assert_eq!(outlines[0].title.as_deref(), Some("Tom & Jerry"));
+
+        // Importing what was exported finds the channel without any network requests, and
+        // reports other feeds as unresolved
+        let data = data.replace(
+            "</body>",
+            r#"<outline text="Blog" xmlUrl="https://example.com/feed" /></body>"#,
+        );
+        let report = import(&db, &data)?;
+        assert!(report.added.is_empty());
+        assert_eq!(report.existing, vec!["Tom & Jerry".to_string()]);
+        assert_eq!(report.unresolved.len(), 1);
+        assert_eq!(report.unresolved[0].0, "Blog");
+        Ok(())
+    }
+}
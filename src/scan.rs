@@ -0,0 +1,446 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+
+use crate::common::{mime_for_path, VideoStatus};
+use crate::config::Config;
+use crate::db::{DBVideoInfo, Database};
+
+/// Placeholder in `filename_format` which youtube-dl replaces with the video ID
+const ID_PLACEHOLDER: &str = "%(id)s";
+
+/// Filename endings used by youtube-dl for incomplete downloads
+const PARTIAL_SUFFIXES: &[&str] = &[".part", ".ytdl", ".temp"];
+
+/// Text in the filename template directly following the video ID, up to the next placeholder
+/// or directory separator. Returns `None` if the template does not contain the video ID
+pub fn id_suffix(filename_format: &str) -> Option<&str> {
+    let idx = filename_format.find(ID_PLACEHOLDER)?;
+    let rest = &filename_format[idx + ID_PLACEHOLDER.len()..];
+    let end = rest.find(['%', '/', '\\']).unwrap_or(rest.len());
+    Some(&rest[..end])
+}
+
+/// Locate a video ID within `filename`. The ID must be directly followed by `suffix` (from
+/// `id_suffix`), and `is_known` is used to check candidate ID's. The longest matching ID is
+/// preferred, as ID's can contain characters like `_` which are also used as separators
+pub fn find_video_id<'a>(
+    filename: &'a str,
+    suffix: &str,
+    is_known: impl Fn(&str) -> bool,
+) -> Option<&'a str> {
+    // Video ID's are short, so no need to check beyond this many bytes before the suffix
+    const MAX_ID_LEN: usize = 64;
+
+    let ends: Vec<usize> = if suffix.is_empty() {
+        vec![filename.len()]
+    } else {
+        filename.match_indices(suffix).map(|(i, _)| i).collect()
+    };
+
+    for end in ends {
+        for start in end.saturating_sub(MAX_ID_LEN)..end {
+            if !filename.is_char_boundary(start) {
+                continue;
+            }
+            let candidate = &filename[start..end];
+            if is_known(candidate) {
+                return Some(candidate);
+            }
+        }
+    }
+    None
+}
+
+/// Check if filename is an incomplete download
+pub fn is_partial(filename: &str) -> bool {
+    PARTIAL_SUFFIXES.iter().any(|s| filename.ends_with(s)) || filename.contains(".part-Frag")
+}
+
+/// Recursively list all files in directory
+fn walk(dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to list {:?}", dir))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, out)?;
+        } else {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Incomplete download files in the download directory belonging to the given video
+pub fn partial_files(cfg: &Config, video_id: &str) -> Result<Vec<PathBuf>> {
+    let suffix = match id_suffix(&cfg.filename_format) {
+        Some(s) => s,
+        None => return Ok(vec![]),
+    };
+    if !cfg.download_dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut files = vec![];
+    walk(&cfg.download_dir, &mut files)?;
+    Ok(files
+        .into_iter()
+        .filter(|f| match f.file_name().and_then(|n| n.to_str()) {
+            Some(name) => {
+                is_partial(name) && find_video_id(name, suffix, |c| c == video_id).is_some()
+            }
+            None => false,
+        })
+        .collect())
+}
+
+/// Whether a file was written alongside videos, either by the library layout or as a recorded
+/// sidecar, so should not be reported as an orphan
+fn is_known_extra(path: &Path, name: &str, sidecars: &HashSet<PathBuf>) -> bool {
+    crate::layout::is_show_file(name) || sidecars.contains(path)
+}
+
+/// Outcome of comparing the database to the download directory
+#[derive(Debug, Default)]
+struct ScanReport {
+    /// Videos which were not marked as grabbed but have a file on disk
+    adopted: Vec<(String, PathBuf)>,
+    /// Grabbed videos with a file found in a different location than recorded
+    relocated: Vec<(String, PathBuf)>,
+    /// Grabbed videos whose file no longer exists
+    missing: Vec<String>,
+    /// Files which do not belong to any known video
+    orphans: Vec<PathBuf>,
+    /// Incomplete downloads
+    partial: Vec<PathBuf>,
+}
+
+/// Walk the download directory, matching files to videos using the ID embedded in them via
+/// `filename_format`, then update the database to match what is on disk. With `dry_run` the
+/// changes are only reported
+pub fn scan(dry_run: bool) -> Result<()> {
+    let cfg = Config::load()?;
+    let db = Database::open(&cfg)?;
+    let report = scan_db(&cfg, &db, dry_run)?;
+
+    if dry_run {
+        println!("Dry run, database has not been modified");
+    }
+    println!(
+        "Adopted {} files for videos not marked as grabbed:",
+        report.adopted.len()
+    );
+    for (id, path) in &report.adopted {
+        println!("  {} - {}", id, path.display());
+    }
+    println!(
+        "Updated location of {} moved files:",
+        report.relocated.len()
+    );
+    for (id, path) in &report.relocated {
+        println!("  {} - {}", id, path.display());
+    }
+    println!(
+        "Reset {} grabbed videos with missing files to new:",
+        report.missing.len()
+    );
+    for id in &report.missing {
+        println!("  {}", id);
+    }
+    println!(
+        "Found {} files not belonging to any video:",
+        report.orphans.len()
+    );
+    for path in &report.orphans {
+        println!("  {}", path.display());
+    }
+    println!("Found {} partially downloaded files:", report.partial.len());
+    for path in &report.partial {
+        println!("  {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Compare the database to the download directory, updating it unless `dry_run` is set
+fn scan_db(cfg: &Config, db: &Database, dry_run: bool) -> Result<ScanReport> {
+    let suffix = id_suffix(&cfg.filename_format).ok_or_else(|| {
+        anyhow::anyhow!(
+            "filename_format {:?} does not contain {} so files cannot be matched to videos",
+            cfg.filename_format,
+            ID_PLACEHOLDER
+        )
+    })?;
+
+    let videos = crate::db::all_videos(db, &Default::default(), i64::MAX, 0)?;
+    let by_id: HashMap<&str, &DBVideoInfo> =
+        videos.iter().map(|v| (v.info.id.as_str(), v)).collect();
+
+    let root = std::fs::canonicalize(&cfg.download_dir)
+        .with_context(|| format!("Download directory {:?} not found", &cfg.download_dir))?;
+    let mut files = vec![];
+    walk(&root, &mut files)?;
+    // Recorded paths may be relative, while those found by walking the directory are not
+    let sidecars: HashSet<PathBuf> = crate::db::sidecar_paths(db)?
+        .into_iter()
+        .map(|p| std::fs::canonicalize(&p).unwrap_or(p))
+        .collect();
+    info!("Scanning {} files in {:?}", files.len(), &root);
+
+    let mut report = ScanReport::default();
+
+    // Map of video SQL ID to the media file found for it
+    let mut found: HashMap<i64, PathBuf> = HashMap::new();
+    for f in files {
+        let name = match f.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n,
+            None => {
+                report.orphans.push(f.clone());
+                continue;
+            }
+        };
+        if is_partial(name) {
+            report.partial.push(f.clone());
+            continue;
+        }
+        if is_known_extra(&f, name, &sidecars) {
+            debug!("Ignoring layout or sidecar file {:?}", &f);
+            continue;
+        }
+        match find_video_id(name, suffix, |c| by_id.contains_key(c)) {
+            Some(vid) => {
+                let mime = mime_for_path(&f);
+                if mime.starts_with("video/") || mime.starts_with("audio/") {
+                    found.entry(by_id[vid].id).or_insert_with(|| f.clone());
+                } else {
+                    debug!("Ignoring non-media file {:?} for {}", &f, vid);
+                }
+            }
+            None => report.orphans.push(f.clone()),
+        }
+    }
+
+    for v in &videos {
+        let on_disk = found.get(&v.id);
+        match (&v.status, on_disk) {
+            (VideoStatus::Grabbed, Some(path))
+                if v.file_path.as_ref().map(PathBuf::from).as_ref() != Some(path) =>
+            {
+                report.relocated.push((v.info.id.clone(), path.clone()));
+                if !dry_run {
+                    v.set_file_path(db, path.to_str())?;
+                    // Sidecars are normally moved along with the media file
+                    crate::sidecar::record(db, v, path)?;
+                }
+            }
+            (VideoStatus::Grabbed, None) => {
+                // Files may be stored outside the download directory, so check the recorded path
+                let exists = v
+                    .file_path
+                    .as_ref()
+                    .map(|p| Path::new(p).exists())
+                    .unwrap_or(false);
+                if !exists {
+                    report.missing.push(v.info.id.clone());
+                    if !dry_run {
+                        v.set_file_path(db, None)?;
+                        v.set_status(db, VideoStatus::New)?;
+                    }
+                }
+            }
+            (VideoStatus::New, Some(path)) | (VideoStatus::GrabError, Some(path)) => {
+                report.adopted.push((v.info.id.clone(), path.clone()));
+                if !dry_run {
+                    v.set_file_path(db, path.to_str())?;
+                    v.set_status(db, VideoStatus::Grabbed)?;
+                    crate::sidecar::record(db, v, path)?;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_id_suffix() {
+        assert_eq!(
+            id_suffix("%(uploader)s__%(upload_date)s_%(title)s__%(id)s.%(ext)s"),
+            Some(".")
+        );
+        assert_eq!(id_suffix("%(id)s"), Some(""));
+        assert_eq!(id_suffix("%(uploader)s/%(id)s/video.%(ext)s"), Some(""));
+        assert_eq!(id_suffix("%(title)s.%(ext)s"), None);
+    }
+
+    #[test]
+    fn test_find_video_id() {
+        let known = |c: &str| c == "dQw4w9WgXcQ" || c == "_abc-defghi";
+
+        assert_eq!(
+            find_video_id("Someone__20200101_A_title__dQw4w9WgXcQ.mp4", ".", known),
+            Some("dQw4w9WgXcQ")
+        );
+        // Format-specific intermediate files, and titles containing the suffix
+        assert_eq!(
+            find_video_id("Someone__20200101_v1.2__dQw4w9WgXcQ.f137.mp4", ".", known),
+            Some("dQw4w9WgXcQ")
+        );
+        // ID starting with the separator character
+        assert_eq!(
+            find_video_id("Someone__20200101_Title___abc-defghi.webm", ".", known),
+            Some("_abc-defghi")
+        );
+        assert_eq!(
+            find_video_id("Someone__20200101_Title__unknownid12.mp4", ".", known),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_known_extra() {
+        let sidecars: HashSet<PathBuf> = vec![PathBuf::from("/dl/Show/cover.webp")]
+            .into_iter()
+            .collect();
+        assert!(is_known_extra(
+            Path::new("/dl/Show/poster.jpg"),
+            "poster.jpg",
+            &sidecars
+        ));
+        assert!(is_known_extra(
+            Path::new("/dl/Show/tvshow.nfo"),
+            "tvshow.nfo",
+            &sidecars
+        ));
+        assert!(is_known_extra(
+            Path::new("/dl/Show/cover.webp"),
+            "cover.webp",
+            &sidecars
+        ));
+        assert!(!is_known_extra(
+            Path::new("/dl/Show/other.jpg"),
+            "other.jpg",
+            &sidecars
+        ));
+    }
+
+    #[test]
+    fn test_scan() -> Result<()> {
+        use crate::common::{ChannelID, YoutubeID};
+        use crate::db::Channel;
+        use crate::sidecar::SidecarKind;
+        use crate::youtube::VideoInfo;
+
+        let dir = std::env::temp_dir().join(format!("vidl-test-scan-{}", std::process::id()));
+        let mut cfg = Config::load_from(&dir.join("config"))?;
+        cfg.download_dir = dir.join("dl");
+        std::fs::create_dir_all(cfg.download_dir.join("Moved"))?;
+        let dl = std::fs::canonicalize(&cfg.download_dir)?;
+
+        let db = Database::open_in_memory()?;
+        let cid = ChannelID::Youtube(YoutubeID {
+            id: "UCUBfKCp83QT19JCUekEdxOQ".into(),
+        });
+        let chan = Channel::create(&db, &cid, "test channel", "")?;
+        let add = |id: &str| {
+            chan.add_video(
+                &db,
+                &VideoInfo {
+                    id: id.into(),
+                    url: format!("http://example.com/watch?v={}", id),
+                    title: "A title".into(),
+                    description: "".into(),
+                    thumbnail_url: "".into(),
+                    published_at: chrono::Utc::now(),
+                },
+            )
+        };
+        let adopt = add("adoptid0001")?;
+        let moved = add("movedid0001")?;
+        let missing = add("missingid01")?;
+
+        // Not marked as grabbed, but on disk
+        let adopt_file = dl.join("Someone__20200101_Title__adoptid0001.mp4");
+        std::fs::write(&adopt_file, b"")?;
+
+        // Grabbed, then moved along with its thumbnail
+        let old_file = dl.join("Someone__20200101_Title__movedid0001.mp4");
+        let moved_file = dl.join("Moved/Someone__20200101_Title__movedid0001.mp4");
+        let moved_thumb = dl.join("Moved/Someone__20200101_Title__movedid0001.jpg");
+        std::fs::write(&moved_file, b"")?;
+        std::fs::write(&moved_thumb, b"")?;
+        moved.set_status(&db, VideoStatus::Grabbed)?;
+        moved.set_file_path(&db, old_file.to_str())?;
+        moved.add_sidecar(
+            &db,
+            SidecarKind::Thumbnail,
+            &old_file.with_extension("jpg").to_string_lossy(),
+        )?;
+
+        // Grabbed, but the file is gone
+        missing.set_status(&db, VideoStatus::Grabbed)?;
+        missing.set_file_path(&db, dl.join("gone.mp4").to_str())?;
+
+        let partial_file = dl.join("Someone__20200101_Title__otherid0001.mp4.part");
+        std::fs::write(&partial_file, b"")?;
+
+        let get = |v: &DBVideoInfo| DBVideoInfo::get_by_sqlid(&db, v.id);
+
+        // Dry run reports changes without making them
+        let report = scan_db(&cfg, &db, true)?;
+        assert_eq!(
+            report.adopted,
+            vec![("adoptid0001".into(), adopt_file.clone())]
+        );
+        assert_eq!(
+            report.relocated,
+            vec![("movedid0001".into(), moved_file.clone())]
+        );
+        assert_eq!(report.missing, vec!["missingid01".to_string()]);
+        assert_eq!(report.partial, vec![partial_file.clone()]);
+        assert!(report.orphans.is_empty());
+        assert_eq!(get(&adopt)?.status, VideoStatus::New);
+        assert_eq!(get(&moved)?.file_path, old_file.to_str().map(String::from));
+        assert_eq!(get(&missing)?.status, VideoStatus::Grabbed);
+
+        scan_db(&cfg, &db, false)?;
+        let a = get(&adopt)?;
+        assert_eq!(a.status, VideoStatus::Grabbed);
+        assert_eq!(a.file_path, adopt_file.to_str().map(String::from));
+        let m = get(&moved)?;
+        assert_eq!(m.file_path, moved_file.to_str().map(String::from));
+        assert_eq!(
+            m.sidecars(&db)?,
+            vec![(
+                SidecarKind::Thumbnail,
+                moved_thumb.to_string_lossy().to_string()
+            )]
+        );
+        let m = get(&missing)?;
+        assert_eq!(m.status, VideoStatus::New);
+        assert_eq!(m.file_path, None);
+
+        // Nothing left to change
+        let report = scan_db(&cfg, &db, false)?;
+        assert!(report.adopted.is_empty());
+        assert!(report.relocated.is_empty());
+        assert!(report.missing.is_empty());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_partial() {
+        assert!(is_partial("a__dQw4w9WgXcQ.mp4.part"));
+        assert!(is_partial("a__dQw4w9WgXcQ.f137.mp4.part-Frag12"));
+        assert!(is_partial("a__dQw4w9WgXcQ.mp4.ytdl"));
+        assert!(!is_partial("a__dQw4w9WgXcQ.mp4"));
+    }
+}
@@ -0,0 +1,189 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+
+use crate::config::QualityProfile;
+use crate::db::{Channel, DBVideoInfo, Database};
+
+/// Type of extra file stored alongside a downloaded video
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SidecarKind {
+    Subtitle,
+    Thumbnail,
+    Metadata,
+}
+
+impl SidecarKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            SidecarKind::Subtitle => "subtitle",
+            SidecarKind::Thumbnail => "thumbnail",
+            SidecarKind::Metadata => "metadata",
+        }
+    }
+
+    pub fn from_str(kind: &str) -> Result<Self> {
+        match kind {
+            "subtitle" => Ok(SidecarKind::Subtitle),
+            "thumbnail" => Ok(SidecarKind::Thumbnail),
+            "metadata" => Ok(SidecarKind::Metadata),
+            _ => Err(anyhow::anyhow!("Unknown sidecar kind {:?}", kind)),
+        }
+    }
+
+    /// Determine type of file from its extension, returning `None` if it is not a sidecar
+    fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "vtt" | "srt" | "ass" | "ttml" | "srv3" => Some(SidecarKind::Subtitle),
+            "jpg" | "jpeg" | "png" | "webp" => Some(SidecarKind::Thumbnail),
+            "json" | "nfo" => Some(SidecarKind::Metadata),
+            _ => None,
+        }
+    }
+}
+
+/// Contents of the metadata file written next to downloaded videos
+#[derive(Serialize, Debug)]
+struct SidecarMetadata<'a> {
+    id: &'a str,
+    title: &'a str,
+    description: &'a str,
+    url: &'a str,
+    thumbnail_url: &'a str,
+    published_at: String,
+    channel: &'a str,
+    channel_id: &'a str,
+    service: &'a str,
+}
+
+/// Write JSON file describing the video next to the downloaded file, returning its path
+fn write_metadata(media: &Path, video: &DBVideoInfo, chan: &Channel) -> Result<PathBuf> {
+    let meta = SidecarMetadata {
+        id: &video.info.id,
+        title: &video.info.title,
+        description: &video.info.description,
+        url: &video.info.url,
+        thumbnail_url: &video.info.thumbnail_url,
+        published_at: video.info.published_at.to_rfc3339(),
+        channel: &chan.title,
+        channel_id: &chan.chanid,
+        service: chan.service.as_str(),
+    };
+
+    let path = media.with_extension("json");
+    let f =
+        std::fs::File::create(&path).with_context(|| format!("Failed to create {:?}", &path))?;
+    serde_json::to_writer_pretty(f, &meta)?;
+    Ok(path)
+}
+
+/// Find sidecar files next to the downloaded file. These share its name minus the extension,
+/// e.g `video.en.vtt` and `video.jpg` for `video.mp4`
+fn find_sidecars(media: &Path) -> Result<Vec<(SidecarKind, PathBuf)>> {
+    let (dir, stem) = match (media.parent(), media.file_stem().and_then(|s| s.to_str())) {
+        (Some(dir), Some(stem)) => (dir, stem),
+        _ => return Ok(vec![]),
+    };
+    let prefix = format!("{}.", stem);
+
+    let mut ret = vec![];
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to list {:?}", dir))? {
+        let path = entry?.path();
+        if path == media {
+            continue;
+        }
+        let name_matches = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.starts_with(&prefix))
+            .unwrap_or(false);
+        if let (true, Some(kind)) = (name_matches, SidecarKind::from_path(&path)) {
+            ret.push((kind, path));
+        }
+    }
+    Ok(ret)
+}
+
+/// Create the sidecar files requested by the quality profile for a newly downloaded video, and
+/// record all sidecars found next to it in the database
+pub fn process(
+    db: &Database,
+    video: &DBVideoInfo,
+    chan: &Channel,
+    media: &Path,
+    profile: &QualityProfile,
+) -> Result<()> {
+    if profile.metadata {
+        let path = write_metadata(media, video, chan)?;
+        debug!("Wrote metadata to {:?}", &path);
+    }
+    record(db, video, media)
+}
+
+/// Replace the sidecars recorded for a video with those found next to its downloaded file
+pub fn record(db: &Database, video: &DBVideoInfo, media: &Path) -> Result<()> {
+    video.clear_sidecars(db)?;
+    for (kind, path) in find_sidecars(media)? {
+        debug!("Found {} sidecar {:?}", kind.as_str(), &path);
+        let path = std::fs::canonicalize(&path).unwrap_or(path);
+        video.add_sidecar(db, kind, &path.to_string_lossy())?;
+    }
+    Ok(())
+}
+
+/// Delete the downloaded file for a video along with all its sidecars, forgetting them in the
+/// database
+pub fn delete_files(db: &Database, video: &DBVideoInfo) -> Result<()> {
+    let mut paths: Vec<String> = video.sidecars(db)?.into_iter().map(|(_, p)| p).collect();
+    paths.extend(video.file_path.clone());
+
+    for p in paths {
+        info!("Deleting {:?}", &p);
+        match std::fs::remove_file(&p) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e).with_context(|| format!("Failed to delete {:?}", &p)),
+        }
+    }
+
+    video.clear_sidecars(db)?;
+    video.set_file_path(db, None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_sidecars() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("vidl-test-sidecar-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        for name in &[
+            "a__abc.mp4",
+            "a__abc.en.vtt",
+            "a__abc.jpg",
+            "a__abc.json",
+            "a__abc.mp4.part",
+            "b__def.jpg",
+        ] {
+            std::fs::write(dir.join(name), b"")?;
+        }
+
+        let mut found = find_sidecars(&dir.join("a__abc.mp4"))?;
+        found.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            found,
+            vec![
+                (SidecarKind::Subtitle, dir.join("a__abc.en.vtt")),
+                (SidecarKind::Thumbnail, dir.join("a__abc.jpg")),
+                (SidecarKind::Metadata, dir.join("a__abc.json")),
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::{Context, Result};
+
+use crate::common::Service;
+use crate::config::Config;
+use crate::db::Database;
+use crate::import::{add_channel, ImportReport};
+
+/// Channel subscribed to in a Google Takeout export
+#[derive(Debug, PartialEq)]
+pub struct Subscription {
+    pub channel_id: String,
+    pub title: String,
+}
+
+/// Entry of the JSON `subscriptions.json` from older exports, as returned by the YouTube API
+#[derive(Deserialize, Debug)]
+struct JsonSubscription {
+    snippet: JsonSnippet,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct JsonSnippet {
+    #[serde(default)]
+    title: String,
+    resource_id: JsonResourceId,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct JsonResourceId {
+    channel_id: String,
+}
+
+/// Split a line of CSV into fields, handling quoted fields which may contain commas and
+/// doubled quotes
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Whether a string looks like a YouTube channel ID, such as `UCUBfKCp83QT19JCUekEdxOQ`
+fn is_channel_id(s: &str) -> bool {
+    s.len() == 24 && s.starts_with("UC")
+}
+
+/// Parse `subscriptions.csv`, with columns of channel ID, URL and title. The header row is
+/// translated into the account's language, so is recognised by not containing a channel ID
+fn parse_csv(data: &str) -> Result<Vec<Subscription>> {
+    let mut subs = vec![];
+    for (num, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = csv_fields(line.trim_end_matches('\r'));
+        let id = fields[0].trim();
+        if !is_channel_id(id) {
+            if num == 0 {
+                continue;
+            }
+            return Err(anyhow::anyhow!(
+                "Line {}: {:?} is not a channel ID",
+                num + 1,
+                id
+            ));
+        }
+        subs.push(Subscription {
+            channel_id: id.into(),
+            title: fields
+                .get(2)
+                .map(|t| t.trim().to_string())
+                .unwrap_or_default(),
+        });
+    }
+    Ok(subs)
+}
+
+/// Parse a Takeout subscriptions file, either the CSV from current exports or the JSON from
+/// older ones
+pub fn parse(data: &str) -> Result<Vec<Subscription>> {
+    // Strip byte order mark, which may precede the header row
+    let data = data.trim_start_matches('\u{feff}');
+    if data.trim_start().starts_with('[') {
+        let entries: Vec<JsonSubscription> =
+            serde_json::from_str(data).context("Failed to parse subscriptions JSON")?;
+        Ok(entries
+            .into_iter()
+            .map(|e| Subscription {
+                channel_id: e.snippet.resource_id.channel_id,
+                title: e.snippet.title,
+            })
+            .collect())
+    } else {
+        parse_csv(data)
+    }
+}
+
+/// Add a channel for each subscription. Channels already added are skipped without any network
+/// requests
+pub fn import(db: &Database, subs: &[Subscription], dry_run: bool) -> Result<ImportReport> {
+    let mut report = ImportReport::new(dry_run);
+    for s in subs {
+        let cid = Service::Youtube.get_channel_id(&s.channel_id);
+        let label = if s.title.is_empty() {
+            &s.channel_id
+        } else {
+            &s.title
+        };
+        report.record(label, add_channel(db, &cid, Some(label), dry_run));
+    }
+    Ok(report)
+}
+
+/// Import subscriptions from a Takeout file, printing a summary
+pub fn import_file(path: &str, dry_run: bool) -> Result<()> {
+    let cfg = Config::load()?;
+    let db = Database::open(&cfg)?;
+
+    let data = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    let subs = parse(&data)?;
+    let report = import(&db, &subs, dry_run)?;
+    println!("{}", report.summary());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let csv = "\u{feff}Channel Id,Channel Url,Channel Title\r\n\
+            UCUBfKCp83QT19JCUekEdxOQ,http://www.youtube.com/channel/UCUBfKCp83QT19JCUekEdxOQ,Climbing\r\n\
+            UCabcdefghijklmnopqrstuv,http://www.youtube.com/channel/UCabcdefghijklmnopqrstuv,\"Tom, \"\"Jerry\"\"\"\r\n\r\n";
+        let expected = vec![
+            Subscription {
+                channel_id: "UCUBfKCp83QT19JCUekEdxOQ".into(),
+                title: "Climbing".into(),
+            },
+            Subscription {
+                channel_id: "UCabcdefghijklmnopqrstuv".into(),
+                title: "Tom, \"Jerry\"".into(),
+            },
+        ];
+        assert_eq!(parse(csv)?, expected);
+
+        let json = r#"[
+            {"kind": "youtube#subscription", "snippet": {"title": "Climbing",
+                "resourceId": {"kind": "youtube#channel", "channelId": "UCUBfKCp83QT19JCUekEdxOQ"}}},
+            {"snippet": {"title": "Tom, \"Jerry\"",
+                "resourceId": {"channelId": "UCabcdefghijklmnopqrstuv"}}}
+        ]"#;
+        assert_eq!(parse(json)?, expected);
+
+        assert!(parse("Channel Id,Channel Url\nnonsense,http://example.com").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run() -> Result<()> {
+        let db = Database::open_in_memory()?;
+        let cid = Service::Youtube.get_channel_id("UCUBfKCp83QT19JCUekEdxOQ");
+        crate::db::Channel::create(&db, &cid, "Climbing channel", "")?;
+
+        let subs = parse(
+            "UCUBfKCp83QT19JCUekEdxOQ,,Climbing\nUCabcdefghijklmnopqrstuv,,Tom\nUCzyxwvutsrqponmlkjihgfe,,",
+        )?;
+        let report = import(&db, &subs, true)?;
+        assert_eq!(report.existing, vec!["Climbing channel".to_string()]);
+        assert_eq!(
+            report.added,
+            vec!["Tom".to_string(), "UCzyxwvutsrqponmlkjihgfe".to_string()]
+        );
+        assert!(report.unresolved.is_empty());
+        assert!(report.summary().starts_with("Would add 2 channels"));
+        assert_eq!(crate::db::list_channels(&db)?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use chrono::TimeZone;
+use lazy_static::lazy_static;
+use log::{debug, error};
+
+use crate::common::lock;
+use crate::config::Config;
+
+/// How long to wait for a thumbnail server before giving up
+const FETCH_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Largest image which will be cached
+const MAX_IMAGE_BYTES: u64 = 5 * 1024 * 1024;
+
+/// How long to wait after failing to fetch an image before trying again
+const RETRY_FAILED_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Details stored alongside each cached image
+#[derive(Serialize, Deserialize, Debug)]
+struct Meta {
+    url: String,
+    content_type: String,
+    /// Unix timestamp
+    fetched_at: i64,
+}
+
+/// Cached image, as served by the web interface
+pub struct Thumbnail {
+    pub data: Vec<u8>,
+    pub content_type: String,
+    pub fetched_at: chrono::DateTime<chrono::Utc>,
+    /// Identifies this version of the image, for `ETag` headers
+    pub etag: String,
+}
+
+struct Entry {
+    /// Bytes used on disk, image plus metadata
+    size: u64,
+    last_used: SystemTime,
+}
+
+/// Thumbnail images fetched from remote URLs, stored on disk so they survive restarts. When the
+/// total size exceeds the limit, the least recently used images are removed
+pub struct ThumbnailCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: HashMap<String, Entry>,
+    total_bytes: u64,
+    /// When fetching each URL last failed, so dead URLs are not requested repeatedly
+    failures: HashMap<String, Instant>,
+}
+
+/// Name of files for an image, from a hash of its URL
+fn key(url: &str) -> String {
+    blake2b_simd::Params::new()
+        .hash_length(16)
+        .hash(url.as_bytes())
+        .to_hex()
+        .to_string()
+}
+
+impl ThumbnailCache {
+    /// Open cache in `dir`, finding existing images. Last use times are not stored, so the
+    /// time images were written is used instead
+    pub fn open(dir: &Path, max_bytes: u64) -> Result<ThumbnailCache> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create thumbnail cache dir {:?}", dir))?;
+
+        let mut entries = HashMap::new();
+        for f in std::fs::read_dir(dir)? {
+            let path = f?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("img") {
+                continue;
+            }
+            let key = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(k) => k.to_string(),
+                None => continue,
+            };
+            let meta_size = match std::fs::metadata(path.with_extension("json")) {
+                Ok(m) => m.len(),
+                // Incomplete entry, e.g from crash while writing
+                Err(_) => {
+                    std::fs::remove_file(&path)?;
+                    continue;
+                }
+            };
+            let m = std::fs::metadata(&path)?;
+            entries.insert(
+                key,
+                Entry {
+                    size: m.len() + meta_size,
+                    last_used: m.modified()?,
+                },
+            );
+        }
+
+        let total_bytes = entries.values().map(|e| e.size).sum();
+        let mut cache = ThumbnailCache {
+            dir: dir.into(),
+            max_bytes,
+            entries,
+            total_bytes,
+            failures: HashMap::new(),
+        };
+        cache.evict()?;
+        Ok(cache)
+    }
+
+    fn paths(&self, key: &str) -> (PathBuf, PathBuf) {
+        (
+            self.dir.join(format!("{}.img", key)),
+            self.dir.join(format!("{}.json", key)),
+        )
+    }
+
+    pub fn contains(&self, url: &str) -> bool {
+        self.entries.contains_key(&key(url))
+    }
+
+    pub fn get(&mut self, url: &str) -> Result<Option<Thumbnail>> {
+        let key = key(url);
+        if !self.entries.contains_key(&key) {
+            return Ok(None);
+        }
+        let (img_path, meta_path) = self.paths(&key);
+        let read = || -> Result<(Vec<u8>, Meta)> {
+            let data = std::fs::read(&img_path)?;
+            let meta: Meta = serde_json::from_slice(&std::fs::read(&meta_path)?)?;
+            Ok((data, meta))
+        };
+        let (data, meta) = match read() {
+            Ok(x) => x,
+            Err(e) => {
+                // Treat as not cached, so it gets fetched again
+                error!("Failed to read cached thumbnail for {} - {:?}", url, e);
+                self.remove(&key)?;
+                return Ok(None);
+            }
+        };
+        if let Some(e) = self.entries.get_mut(&key) {
+            e.last_used = SystemTime::now();
+        }
+
+        Ok(Some(Thumbnail {
+            etag: format!("\"{}-{}\"", key, meta.fetched_at),
+            data,
+            content_type: meta.content_type,
+            fetched_at: chrono::Utc.timestamp_opt(meta.fetched_at, 0).unwrap(),
+        }))
+    }
+
+    pub fn insert(&mut self, url: &str, content_type: &str, data: &[u8]) -> Result<()> {
+        let key = key(url);
+        self.remove(&key)?;
+
+        let meta = serde_json::to_vec(&Meta {
+            url: url.into(),
+            content_type: content_type.into(),
+            fetched_at: chrono::Utc::now().timestamp(),
+        })?;
+        // Metadata is written first, as an image without it is considered incomplete
+        let (img_path, meta_path) = self.paths(&key);
+        std::fs::write(&meta_path, &meta)?;
+        let tmp_path = img_path.with_extension("tmp");
+        std::fs::write(&tmp_path, data)?;
+        std::fs::rename(&tmp_path, &img_path)?;
+
+        let size = (data.len() + meta.len()) as u64;
+        self.total_bytes += size;
+        self.entries.insert(
+            key,
+            Entry {
+                size,
+                last_used: SystemTime::now(),
+            },
+        );
+        self.evict()
+    }
+
+    pub fn record_failure(&mut self, url: &str) {
+        self.failures.insert(url.into(), Instant::now());
+    }
+
+    /// Whether fetching `url` failed too recently to try again
+    pub fn recently_failed(&mut self, url: &str) -> bool {
+        self.failures
+            .retain(|_, failed| failed.elapsed() < RETRY_FAILED_AFTER);
+        self.failures.contains_key(url)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        let (img_path, meta_path) = self.paths(key);
+        for p in &[img_path, meta_path] {
+            match std::fs::remove_file(p) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("Failed to remove {:?}", p))
+                }
+                _ => (),
+            }
+        }
+        if let Some(e) = self.entries.remove(key) {
+            self.total_bytes -= e.size;
+        }
+        Ok(())
+    }
+
+    /// Remove least recently used images until within the size limit
+    fn evict(&mut self) -> Result<()> {
+        while self.total_bytes > self.max_bytes {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone());
+            match oldest {
+                Some(k) => {
+                    debug!("Evicting thumbnail {}", k);
+                    self.remove(&k)?;
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    /// Opened on first use, so a bad config is reported as an error rather than a panic
+    static ref CACHE: Mutex<Option<ThumbnailCache>> = Mutex::new(None);
+}
+
+/// Run `f` with the shared cache, opening it if needed
+fn with_cache<T>(f: impl FnOnce(&mut ThumbnailCache) -> T) -> Result<T> {
+    let mut cache = lock(&CACHE);
+    if cache.is_none() {
+        let cfg = Config::load()?;
+        *cache = Some(
+            ThumbnailCache::open(cfg.thumbnail_dir(), cfg.thumbnail_cache_size)
+                .context("Failed to open thumbnail cache")?,
+        );
+    }
+    Ok(f(cache.as_mut().expect("cache was just opened")))
+}
+
+/// Cached copy of image at `url`, if it has been fetched
+pub fn get(url: &str) -> Result<Option<Thumbnail>> {
+    let t = with_cache(|c| c.get(url))??;
+    crate::metrics::thumbnail_lookup(t.is_some());
+    Ok(t)
+}
+
+/// Whether `url` cannot currently be served from the cache as fetching it failed recently, or
+/// it is empty (some channels have no thumbnail). Also true if the cache cannot be opened
+pub fn unavailable(url: &str) -> bool {
+    url.is_empty() || with_cache(|c| c.recently_failed(url)).unwrap_or(true)
+}
+
+/// Avoid fetching `url` for a while
+pub fn record_failure(url: &str) {
+    if let Err(e) = with_cache(|c| c.record_failure(url)) {
+        error!("{:?}", e);
+    }
+}
+
+/// Fetch image, returning its content type and data. Anything which isn't an image, or is
+/// suspiciously large, is rejected
+pub fn download(url: &str) -> Result<(String, Vec<u8>)> {
+    let resp = attohttpc::get(url)
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .with_context(|| format!("Failed to fetch thumbnail {}", url))?;
+    let (status, headers, body) = resp.split();
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "Failed to fetch thumbnail {} - status {}",
+            url,
+            status
+        ));
+    }
+    let content_type = headers
+        .get(attohttpc::header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .unwrap_or("image/jpeg")
+        .to_string();
+    if !content_type.starts_with("image/") {
+        return Err(anyhow::anyhow!(
+            "Thumbnail {} is not an image ({})",
+            url,
+            content_type
+        ));
+    }
+
+    // Read one byte more than allowed, to tell if the image was too large
+    let mut data = vec![];
+    body.take(MAX_IMAGE_BYTES + 1)
+        .read_to_end(&mut data)
+        .with_context(|| format!("Failed to read thumbnail {}", url))?;
+    if data.len() as u64 > MAX_IMAGE_BYTES {
+        return Err(anyhow::anyhow!(
+            "Thumbnail {} is larger than {} bytes",
+            url,
+            MAX_IMAGE_BYTES
+        ));
+    }
+    Ok((content_type, data))
+}
+
+/// Download image at `url` into the cache, unless already present or it failed recently
+pub fn fetch(url: &str) -> Result<()> {
+    if unavailable(url) || with_cache(|c| c.contains(url))? {
+        return Ok(());
+    }
+    match download(url) {
+        Ok((content_type, data)) => with_cache(|c| c.insert(url, &content_type, &data))?,
+        Err(e) => {
+            record_failure(url);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cache_eviction() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("vidl-test-thumbs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // Room for two 100 byte images along with their metadata
+        let mut cache = ThumbnailCache::open(&dir, 500)?;
+        cache.insert("http://example.com/1.jpg", "image/jpeg", &[1; 100])?;
+        cache.insert("http://example.com/2.jpg", "image/jpeg", &[2; 100])?;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        // Using the first image makes the second the least recently used
+        assert!(cache.get("http://example.com/1.jpg")?.is_some());
+        cache.insert("http://example.com/3.png", "image/png", &[3; 100])?;
+
+        assert!(cache.contains("http://example.com/1.jpg"));
+        assert!(!cache.contains("http://example.com/2.jpg"));
+        let t = cache.get("http://example.com/3.png")?.unwrap();
+        assert_eq!(t.content_type, "image/png");
+        assert_eq!(t.data, vec![3; 100]);
+
+        // Survives reopening
+        let mut cache = ThumbnailCache::open(&dir, 500)?;
+        assert!(!cache.contains("http://example.com/2.jpg"));
+        assert_eq!(
+            cache.get("http://example.com/1.jpg")?.unwrap().data,
+            vec![1; 100]
+        );
+
+        // Failures are remembered
+        assert!(!cache.recently_failed("http://example.com/4.jpg"));
+        cache.record_failure("http://example.com/4.jpg");
+        assert!(cache.recently_failed("http://example.com/4.jpg"));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_download() {
+        let _img = mockito::mock("GET", "/thumb.jpg")
+            .with_header("content-type", "image/jpeg")
+            .with_body([1, 2, 3])
+            .create();
+        let _html = mockito::mock("GET", "/gone.jpg")
+            .with_header("content-type", "text/html")
+            .with_body("<html>Not here</html>")
+            .create();
+        let _large = mockito::mock("GET", "/large.jpg")
+            .with_header("content-type", "image/jpeg")
+            .with_body(vec![0; MAX_IMAGE_BYTES as usize + 1])
+            .create();
+        let _missing = mockito::mock("GET", "/missing.jpg")
+            .with_status(404)
+            .create();
+
+        let url = |path: &str| format!("{}{}", mockito::server_url(), path);
+        let (ct, data) = download(&url("/thumb.jpg")).unwrap();
+        assert_eq!(ct, "image/jpeg");
+        assert_eq!(data, vec![1, 2, 3]);
+        assert!(download(&url("/gone.jpg")).is_err());
+        assert!(download(&url("/large.jpg")).is_err());
+        assert!(download(&url("/missing.jpg")).is_err());
+    }
+}
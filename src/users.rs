@@ -0,0 +1,517 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, OptionalExtension};
+
+use crate::common::VideoStatus;
+use crate::config::Config;
+use crate::db::{Channel, DBVideoInfo, Database};
+
+/// How long a login session lasts before the user must log in again
+const SESSION_LIFETIME_DAYS: i64 = 30;
+
+/// Name of the cookie holding the session token
+pub const SESSION_COOKIE: &str = "vidl_session";
+
+/// Hex encoded random bytes, suitable for session tokens
+pub fn random_token() -> Result<String> {
+    let mut buf = [0u8; 32];
+    getrandom::getrandom(&mut buf)
+        .map_err(|e| anyhow::anyhow!("Failed to generate random data - {}", e))?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt)
+        .map_err(|e| anyhow::anyhow!("Failed to generate random data - {}", e))?;
+    argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())
+        .context("Failed to hash password")
+}
+
+/// Account used to log into the web interface. Channels and downloads are shared between all
+/// users, while subscriptions and watched/ignored state are per user
+#[derive(Debug, Clone)]
+pub struct User {
+    /// SQL ID number
+    pub id: i64,
+    pub name: String,
+    /// Allowed to manage users and configuration
+    pub admin: bool,
+}
+
+impl User {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<User> {
+        Ok(User {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            admin: row.get(2)?,
+        })
+    }
+
+    pub fn create(db: &Database, name: &str, password: &str, admin: bool) -> Result<User> {
+        if name.is_empty() || password.is_empty() {
+            return Err(anyhow::anyhow!("User name and password must not be empty"));
+        }
+        db.conn
+            .execute(
+                "INSERT INTO user (name, password_hash, admin) VALUES (?1, ?2, ?3)",
+                params![name, hash_password(password)?, admin],
+            )
+            .with_context(|| format!("Failed to create user {:?}", name))?;
+        User::get_by_sqlid(db, db.conn.last_insert_rowid())
+    }
+
+    pub fn get_by_sqlid(db: &Database, id: i64) -> Result<User> {
+        db.conn
+            .query_row(
+                "SELECT id, name, admin FROM user WHERE id=?1",
+                params![id],
+                User::from_row,
+            )
+            .context("Failed to find user")
+    }
+
+    pub fn get_by_name(db: &Database, name: &str) -> Result<User> {
+        db.conn
+            .query_row(
+                "SELECT id, name, admin FROM user WHERE name=?1",
+                params![name],
+                User::from_row,
+            )
+            .with_context(|| format!("Failed to find user {:?}", name))
+    }
+
+    /// Check login details, returning the user if they are correct
+    pub fn authenticate(db: &Database, name: &str, password: &str) -> Result<Option<User>> {
+        let found: Option<(i64, String)> = db
+            .conn
+            .query_row(
+                "SELECT id, password_hash FROM user WHERE name=?1",
+                params![name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        match found {
+            // Restored from a backup without credentials, so has no password until one is set
+            Some((_, ref hash)) if hash.is_empty() => Ok(None),
+            Some((id, hash)) => {
+                if argon2::verify_encoded(&hash, password.as_bytes())? {
+                    Ok(Some(User::get_by_sqlid(db, id)?))
+                } else {
+                    Ok(None)
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_password(&self, db: &Database, password: &str) -> Result<()> {
+        if password.is_empty() {
+            return Err(anyhow::anyhow!("Password must not be empty"));
+        }
+        db.conn
+            .execute(
+                "UPDATE user SET password_hash=?1 WHERE id=?2",
+                params![hash_password(password)?, self.id],
+            )
+            .context("Failed to set password")?;
+        // Log out everywhere
+        db.conn
+            .execute("DELETE FROM session WHERE user=?1", params![self.id])?;
+        Ok(())
+    }
+
+    pub fn set_admin(&self, db: &Database, admin: bool) -> Result<()> {
+        db.conn
+            .execute(
+                "UPDATE user SET admin=?1 WHERE id=?2",
+                params![admin, self.id],
+            )
+            .context("Failed to set admin flag")?;
+        Ok(())
+    }
+
+    /// Remove user along with their sessions, subscriptions and video state
+    pub fn delete(&self, db: &Database) -> Result<()> {
+        let tx = db.conn.unchecked_transaction()?;
+        for table in &["session", "subscription", "user_video"] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE user=?1", table),
+                params![self.id],
+            )?;
+        }
+        tx.execute("DELETE FROM user WHERE id=?1", params![self.id])?;
+        tx.commit().context("Failed to delete user")?;
+        Ok(())
+    }
+
+    /// Start a new login session, returning the token to store in the session cookie
+    pub fn create_session(&self, db: &Database) -> Result<String> {
+        let token = random_token()?;
+        db.conn
+            .execute(
+                "INSERT INTO session (token, user, created_at) VALUES (?1, ?2, ?3)",
+                params![token, self.id, chrono::Utc::now().to_rfc3339()],
+            )
+            .context("Failed to create session")?;
+        Ok(token)
+    }
+
+    /// User logged in with the given session token, if it exists and has not expired
+    pub fn from_session(db: &Database, token: &str) -> Result<Option<User>> {
+        let oldest = chrono::Utc::now() - chrono::Duration::days(SESSION_LIFETIME_DAYS);
+        let user = db
+            .conn
+            .query_row(
+                "SELECT user.id, user.name, user.admin FROM session
+                    JOIN user ON user.id = session.user
+                    WHERE session.token=?1 AND session.created_at > ?2",
+                params![token, oldest.to_rfc3339()],
+                User::from_row,
+            )
+            .optional()?;
+        Ok(user)
+    }
+
+    pub fn subscribe(&self, db: &Database, chan: &Channel) -> Result<()> {
+        db.conn
+            .execute(
+                "INSERT OR IGNORE INTO subscription (user, channel) VALUES (?1, ?2)",
+                params![self.id, chan.id],
+            )
+            .context("Failed to subscribe")?;
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self, db: &Database, chan: &Channel) -> Result<()> {
+        db.conn
+            .execute(
+                "DELETE FROM subscription WHERE user=?1 AND channel=?2",
+                params![self.id, chan.id],
+            )
+            .context("Failed to unsubscribe")?;
+        Ok(())
+    }
+
+    /// SQL ID's of channels the user is subscribed to
+    pub fn subscriptions(&self, db: &Database) -> Result<HashSet<i64>> {
+        let mut stmt = db
+            .conn
+            .prepare("SELECT channel FROM subscription WHERE user=?1")?;
+        let rows = stmt.query_map(params![self.id], |row| row.get(0))?;
+        let mut ret = HashSet::new();
+        for r in rows {
+            ret.insert(r?);
+        }
+        Ok(ret)
+    }
+
+    /// Create the row holding this user's state for a video if it does not exist
+    fn ensure_video_state(&self, db: &Database, video: &DBVideoInfo) -> Result<()> {
+        db.conn.execute(
+            "INSERT OR IGNORE INTO user_video (user, video) VALUES (?1, ?2)",
+            params![self.id, video.id],
+        )?;
+        Ok(())
+    }
+
+    /// Per-user equivalent of `DBVideoInfo::set_watched`
+    pub fn set_watched(&self, db: &Database, video: &DBVideoInfo, watched: bool) -> Result<()> {
+        self.ensure_video_state(db, video)?;
+        db.conn
+            .execute(
+                "UPDATE user_video SET watched=?1,
+                    watch_position=CASE WHEN ?1 THEN 0 ELSE watch_position END
+                    WHERE user=?2 AND video=?3",
+                params![watched, self.id, video.id],
+            )
+            .context("Failed to update watched state")?;
+        Ok(())
+    }
+
+    /// Per-user equivalent of `DBVideoInfo::set_watch_position`
+    pub fn set_watch_position(
+        &self,
+        db: &Database,
+        video: &DBVideoInfo,
+        position: f64,
+    ) -> Result<()> {
+        self.ensure_video_state(db, video)?;
+        db.conn
+            .execute(
+                "UPDATE user_video SET watch_position=?1 WHERE user=?2 AND video=?3",
+                params![position, self.id, video.id],
+            )
+            .context("Failed to update watch position")?;
+        Ok(())
+    }
+
+    /// Hide video from this user, without changing its status for anyone else
+    pub fn set_ignored(&self, db: &Database, video: &DBVideoInfo, ignored: bool) -> Result<()> {
+        self.ensure_video_state(db, video)?;
+        db.conn
+            .execute(
+                "UPDATE user_video SET ignored=?1 WHERE user=?2 AND video=?3",
+                params![ignored, self.id, video.id],
+            )
+            .context("Failed to update ignored state")?;
+        Ok(())
+    }
+
+    /// Per-user equivalent of `Channel::ignore_older_than`
+    pub fn ignore_older_than(
+        &self,
+        db: &Database,
+        chan: &Channel,
+        before: &chrono::DateTime<chrono::Utc>,
+    ) -> Result<usize> {
+        let matching = "SELECT id FROM video
+            WHERE channel=?2 AND published_at < ?3 AND status IN (?4, ?5)";
+        let before = before.to_rfc3339();
+        let args = params![
+            self.id,
+            chan.id,
+            before,
+            VideoStatus::New.as_str(),
+            VideoStatus::GrabError.as_str(),
+        ];
+
+        let tx = db.conn.unchecked_transaction()?;
+        tx.execute(
+            &format!(
+                "INSERT OR IGNORE INTO user_video (user, video)
+                    SELECT ?1, id FROM ({})",
+                matching
+            ),
+            args,
+        )?;
+        let changed = tx.execute(
+            &format!(
+                "UPDATE user_video SET ignored=1
+                    WHERE user=?1 AND ignored=0 AND video IN ({})",
+                matching
+            ),
+            args,
+        )?;
+        tx.commit().context("Failed to ignore older videos")?;
+        Ok(changed)
+    }
+
+    /// Replace the global watched state of videos with this user's, and show videos they have
+    /// ignored as having the `Ignore` status
+    pub fn apply_state(&self, db: &Database, videos: &mut [DBVideoInfo]) -> Result<()> {
+        let mut stmt = db.conn.prepare(
+            "SELECT watched, watch_position, ignored FROM user_video WHERE user=?1 AND video=?2",
+        )?;
+        for v in videos.iter_mut() {
+            let state: Option<(bool, f64, bool)> = stmt
+                .query_row(params![self.id, v.id], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })
+                .optional()?;
+            let (watched, position, ignored) = state.unwrap_or((false, 0.0, false));
+            v.watched = watched;
+            v.watch_position = position;
+            if ignored && (v.status == VideoStatus::New || v.status == VideoStatus::GrabError) {
+                v.status = VideoStatus::Ignore;
+            }
+        }
+        Ok(())
+    }
+
+    /// Per-user equivalent of `db::unwatched_counts`, only counting subscribed channels
+    pub fn unwatched_counts(&self, db: &Database) -> Result<HashMap<i64, i64>> {
+        let mut stmt = db.conn.prepare(
+            "SELECT v.channel, COUNT(*) FROM video v
+                JOIN subscription s ON s.channel = v.channel AND s.user = ?1
+                LEFT JOIN user_video uv ON uv.video = v.id AND uv.user = ?1
+                WHERE v.status != ?2
+                    AND COALESCE(uv.watched, 0) = 0
+                    AND NOT (COALESCE(uv.ignored, 0) = 1 AND v.status IN (?3, ?4))
+                GROUP BY v.channel",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                self.id,
+                VideoStatus::Ignore.as_str(),
+                VideoStatus::New.as_str(),
+                VideoStatus::GrabError.as_str(),
+            ],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let mut ret = HashMap::new();
+        for r in rows {
+            let (chan, count) = r?;
+            ret.insert(chan, count);
+        }
+        Ok(ret)
+    }
+}
+
+/// Remove a login session
+pub fn delete_session(db: &Database, token: &str) -> Result<()> {
+    db.conn
+        .execute("DELETE FROM session WHERE token=?1", params![token])
+        .context("Failed to delete session")?;
+    Ok(())
+}
+
+/// Whether any accounts exist. Without any, the web interface is used without logging in and
+/// all state is global
+pub fn any_users(db: &Database) -> Result<bool> {
+    let count: i64 = db
+        .conn
+        .query_row("SELECT COUNT(*) FROM user", params![], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+pub fn list_users(db: &Database) -> Result<Vec<User>> {
+    let mut stmt = db
+        .conn
+        .prepare("SELECT id, name, admin FROM user ORDER BY name")?;
+    let rows = stmt.query_map(params![], User::from_row)?;
+    let mut ret = vec![];
+    for r in rows {
+        ret.push(r?);
+    }
+    Ok(ret)
+}
+
+/// Read password from stdin, so it does not appear in the process list or shell history
+fn read_password() -> Result<String> {
+    eprint!("Password: ");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Create user from the command line
+pub fn add(name: &str, admin: bool) -> Result<()> {
+    let cfg = Config::load()?;
+    let db = Database::open(&cfg)?;
+    let password = read_password()?;
+    let user = User::create(&db, name, &password, admin)?;
+    println!("Created user {} (admin: {})", user.name, user.admin);
+    Ok(())
+}
+
+/// Change password from the command line
+pub fn passwd(name: &str) -> Result<()> {
+    let cfg = Config::load()?;
+    let db = Database::open(&cfg)?;
+    let user = User::get_by_name(&db, name)?;
+    user.set_password(&db, &read_password()?)?;
+    Ok(())
+}
+
+/// Delete user from the command line
+pub fn remove(name: &str) -> Result<()> {
+    let cfg = Config::load()?;
+    let db = Database::open(&cfg)?;
+    User::get_by_name(&db, name)?.delete(&db)?;
+    Ok(())
+}
+
+/// List users on the command line
+pub fn list() -> Result<()> {
+    let cfg = Config::load()?;
+    let db = Database::open(&cfg)?;
+    for u in list_users(&db)? {
+        println!("{}{}", u.name, if u.admin { " (admin)" } else { "" });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::{ChannelID, YoutubeID};
+    use crate::db::VideoFilter;
+    use crate::youtube::VideoInfo;
+
+    #[test]
+    fn test_login() -> Result<()> {
+        let db = Database::open_in_memory()?;
+        assert!(!any_users(&db)?);
+
+        let alice = User::create(&db, "alice", "hunter2", true)?;
+        assert!(any_users(&db)?);
+        assert!(User::create(&db, "alice", "other", false).is_err());
+
+        assert!(User::authenticate(&db, "alice", "wrong")?.is_none());
+        assert!(User::authenticate(&db, "bob", "hunter2")?.is_none());
+        let found = User::authenticate(&db, "alice", "hunter2")?.unwrap();
+        assert_eq!(found.id, alice.id);
+        assert!(found.admin);
+
+        let token = alice.create_session(&db)?;
+        assert_eq!(User::from_session(&db, &token)?.unwrap().name, "alice");
+        assert!(User::from_session(&db, "bogus")?.is_none());
+
+        // Changing password ends existing sessions
+        alice.set_password(&db, "correct horse")?;
+        assert!(User::from_session(&db, &token)?.is_none());
+        assert!(User::authenticate(&db, "alice", "correct horse")?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_user_state() -> Result<()> {
+        let db = Database::open_in_memory()?;
+        let cid = ChannelID::Youtube(YoutubeID {
+            id: "UCUBfKCp83QT19JCUekEdxOQ".into(),
+        });
+        let chan = Channel::create(&db, &cid, "test channel", "http://example.com/thumb.jpg")?;
+        let video = chan.add_video(
+            &db,
+            &VideoInfo {
+                id: "abc".into(),
+                url: "http://example.com/watch?v=abc".into(),
+                title: "A video".into(),
+                description: "".into(),
+                thumbnail_url: "".into(),
+                published_at: chrono::Utc::now(),
+            },
+        )?;
+
+        let alice = User::create(&db, "alice", "pw", false)?;
+        let bob = User::create(&db, "bob", "pw", false)?;
+        alice.subscribe(&db, &chan)?;
+        bob.subscribe(&db, &chan)?;
+
+        // Ignoring only affects the user who did it
+        alice.set_ignored(&db, &video, true)?;
+        let mut vids = vec![DBVideoInfo::get_by_sqlid(&db, video.id)?];
+        alice.apply_state(&db, &mut vids)?;
+        assert_eq!(vids[0].status, VideoStatus::Ignore);
+        let mut vids = vec![DBVideoInfo::get_by_sqlid(&db, video.id)?];
+        bob.apply_state(&db, &mut vids)?;
+        assert_eq!(vids[0].status, VideoStatus::New);
+
+        let new_for = |user: &User| -> Result<usize> {
+            let filter = VideoFilter {
+                statuses: vec![VideoStatus::New],
+                subscribed_by: Some(user.id),
+                ignored_by: Some(user.id),
+                ..Default::default()
+            };
+            Ok(crate::db::all_videos(&db, &filter, 50, 0)?.len())
+        };
+        assert_eq!(new_for(&alice)?, 0);
+        assert_eq!(new_for(&bob)?, 1);
+
+        // Watched state is also per user
+        bob.set_watched(&db, &video, true)?;
+        assert_eq!(alice.unwatched_counts(&db)?.get(&chan.id), None);
+        assert_eq!(bob.unwatched_counts(&db)?.get(&chan.id), None);
+        alice.set_ignored(&db, &video, false)?;
+        assert_eq!(alice.unwatched_counts(&db)?.get(&chan.id), Some(&1));
+        assert!(!DBVideoInfo::get_by_sqlid(&db, video.id)?.watched);
+
+        // Unsubscribed channels are excluded
+        bob.unsubscribe(&db, &chan)?;
+        assert_eq!(new_for(&bob)?, 0);
+        Ok(())
+    }
+}
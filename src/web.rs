@@ -1,68 +1,23 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{collections::HashMap, time::Duration};
 
 use anyhow::Result;
 use askama::Template;
-use lazy_static::lazy_static;
-use log::{error, info};
-use rouille::{router, Request, Response};
+use chrono::TimeZone;
+use log::{info, warn};
+use rouille::{router, Request, Response, ResponseBody};
 use serde_derive::Serialize;
 
-use crate::common::VideoStatus;
+use crate::auth::Auth;
+use crate::common::{lock, mime_for_path, VideoStatus};
 use crate::config::Config;
-use crate::db::{Channel, DBVideoInfo};
+use crate::db::{Channel, DBVideoInfo, VideoFilter, VideoSort};
+use crate::users::User;
 use crate::worker::WorkerPool;
 
-#[derive(Clone)]
-pub(crate) struct Image {
-    pub(crate) data: Vec<u8>,
-    pub(crate) content_type: String,
-}
-
-pub(crate) struct ImageCache {
-    images: HashMap<String, Image>,
-}
-
-#[derive(Clone)]
-enum ImageCacheResponse {
-    Redirect(String),
-    Image(Image),
-}
-
-impl ImageCache {
-    fn new() -> Self {
-        ImageCache {
-            images: HashMap::new(),
-        }
-    }
-
-    fn get(
-        &mut self,
-        url: String,
-        worker: Arc<Mutex<crate::worker::WorkerPool>>,
-    ) -> Result<ImageCacheResponse> {
-        if self.images.contains_key(&url) {
-            let cached = self.images.get(&url);
-            Ok(ImageCacheResponse::Image((*cached.unwrap()).clone()))
-        } else {
-            let thready_url: String = url.clone();
-            let pool = worker.lock().unwrap();
-            pool.enqueue(crate::worker::WorkItem::ThumbnailCache(thready_url));
-
-            Ok(ImageCacheResponse::Redirect(url.into()))
-        }
-    }
-
-    pub(crate) fn add(&mut self, url: &str, img: Image) {
-        self.images.insert(url.into(), img);
-    }
-}
-
-lazy_static! {
-    pub(crate) static ref IMG_CACHE: Mutex<ImageCache> = Mutex::new(ImageCache::new());
-}
-
 #[derive(Debug, Serialize)]
 pub struct WebChannel {
     id: i64,
@@ -70,6 +25,7 @@ pub struct WebChannel {
     service: String,
     title: String,
     icon: String,
+    unwatched: i64,
 }
 
 impl From<Channel> for WebChannel {
@@ -80,6 +36,7 @@ impl From<Channel> for WebChannel {
             service: src.service.as_str().into(),
             title: src.title,
             icon: src.thumbnail,
+            unwatched: 0,
         }
     }
 }
@@ -106,6 +63,10 @@ pub struct WebVideoInfo<'a> {
     thumbnail_url: String,
     published_at: String,
     status_class: String,
+    last_error: String,
+    attempts: i64,
+    watched: bool,
+    watch_position: f64,
     channel: &'a WebChannel,
 }
 
@@ -133,6 +94,10 @@ impl<'a> From<(DBVideoInfo, &'a WebChannel)> for WebVideoInfo<'a> {
             thumbnail_url: src.info.thumbnail_url,
             published_at: src.info.published_at.to_rfc3339(),
             status_class: status_css_class(src.status),
+            last_error: src.last_error.unwrap_or_default(),
+            attempts: src.attempts,
+            watched: src.watched,
+            watch_position: src.watch_position,
             channel: chan,
         }
     }
@@ -147,38 +112,415 @@ pub struct WebChannelVideos<'a> {
 #[template(path = "channel_list.html")]
 struct ChannelListTemplate<'a> {
     chans: &'a WebChannelList,
+    /// Channels the logged in user is not subscribed to
+    others: &'a WebChannelList,
+    user: Option<&'a User>,
+    /// Whether to link to the admin pages
+    admin: bool,
+    csrf: &'a str,
 }
 
-fn page_chan_list() -> Result<Response> {
-    let cfg = crate::config::Config::load();
+fn page_chan_list(user: Option<&User>, admin: bool, csrf: &str) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
     let db = crate::db::Database::open(&cfg)?;
     let chans = crate::db::list_channels(&db)?;
-    let ret: WebChannelList = chans.into();
 
-    let t = ChannelListTemplate { chans: &ret };
+    let (chans, others, unwatched) = match user {
+        Some(u) => {
+            let subs = u.subscriptions(&db)?;
+            let (chans, others) = chans.into_iter().partition(|c| subs.contains(&c.id));
+            (chans, others, u.unwatched_counts(&db)?)
+        }
+        None => (chans, vec![], crate::db::unwatched_counts(&db)?),
+    };
+    let mut ret: WebChannelList = chans.into();
+    let others: WebChannelList = others.into();
+    for c in ret.channels.iter_mut() {
+        c.unwatched = unwatched.get(&c.id).cloned().unwrap_or(0);
+    }
+
+    let t = ChannelListTemplate {
+        chans: &ret,
+        others: &others,
+        user,
+        admin,
+        csrf,
+    };
 
     let html = t.render()?;
     Ok(Response::html(html))
 }
 
+/// Fields of a submitted form. These are read up front for every request, as the body can
+/// only be read once and is needed for the CSRF check
+struct Form(Vec<(String, String)>);
+
+impl Form {
+    fn read(request: &Request) -> Result<Form> {
+        // Allow API clients to POST without a body
+        if request.method() != "POST" || request.header("Content-Type").is_none() {
+            return Ok(Form(vec![]));
+        }
+        Ok(Form(rouille::input::post::raw_urlencoded_post_input(
+            request,
+        )?))
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginTemplate<'a> {
+    error: Option<&'a str>,
+    csrf: &'a str,
+}
+
+fn page_login_form(error: Option<&str>, csrf: &str) -> Result<Response> {
+    let html = LoginTemplate { error, csrf }.render()?;
+    Ok(Response::html(html))
+}
+
+fn page_login(form: &Form, csrf: &str) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+
+    let name = form.get("name").unwrap_or("");
+    let password = form.get("password").unwrap_or("");
+    match User::authenticate(&db, name, password)? {
+        Some(user) => {
+            info!("User {:?} logged in", &user.name);
+            let token = user.create_session(&db)?;
+            Ok(Response::redirect_303("/").with_additional_header(
+                "Set-Cookie",
+                format!(
+                    "{}={}; Path=/; HttpOnly; SameSite=Lax",
+                    crate::users::SESSION_COOKIE,
+                    token
+                ),
+            ))
+        }
+        None => Ok(
+            page_login_form(Some("Incorrect user name or password"), csrf)?.with_status_code(403),
+        ),
+    }
+}
+
+fn page_logout(request: &Request) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    if let Some(token) = crate::auth::session_token(request) {
+        crate::users::delete_session(&db, &token)?;
+    }
+    Ok(Response::redirect_303("/login").with_additional_header(
+        "Set-Cookie",
+        format!(
+            "{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0",
+            crate::users::SESSION_COOKIE
+        ),
+    ))
+}
+
+fn page_subscribe(
+    request: &Request,
+    user: Option<&User>,
+    chanid: i64,
+    subscribe: bool,
+) -> Result<Response> {
+    let user = match user {
+        Some(u) => u,
+        None => return Ok(Response::text("No user accounts exist").with_status_code(400)),
+    };
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let chan = Channel::get_by_sqlid(&db, chanid)?;
+    if subscribe {
+        user.subscribe(&db, &chan)?;
+    } else {
+        user.unsubscribe(&db, &chan)?;
+    }
+    Ok(redirect_back(request))
+}
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+struct AdminTemplate<'a> {
+    users: &'a [User],
+    config_path: String,
+    config_data: &'a str,
+    message: Option<&'a str>,
+    csrf: &'a str,
+}
+
+fn page_admin(message: Option<&str>, csrf: &str) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let users = crate::users::list_users(&db)?;
+    let current = std::fs::read_to_string(cfg.config_filepath()).unwrap_or_default();
+    let config_data = Config::web_editable(&current)
+        .unwrap_or_else(|e| format!("// {} is invalid - {}", cfg.config_filepath().display(), e));
+
+    let t = AdminTemplate {
+        users: &users,
+        config_path: cfg.config_filepath().display().to_string(),
+        config_data: &config_data,
+        message,
+        csrf,
+    };
+    Ok(Response::html(t.render()?))
+}
+
+fn page_admin_create_user(form: &Form, csrf: &str) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+
+    let name = form.get("name").unwrap_or("");
+    let password = form.get("password").unwrap_or("");
+    let admin = form.get("admin") == Some("1");
+    // First account must be an admin, otherwise nobody could manage users afterwards
+    let first = !crate::users::any_users(&db)?;
+    match User::create(&db, name, password, admin || first) {
+        Ok(u) => {
+            info!("Created user {:?}", &u.name);
+            Ok(Response::redirect_303("/admin"))
+        }
+        Err(e) => Ok(page_admin(Some(&format!("{:#}", e)), csrf)?.with_status_code(400)),
+    }
+}
+
+fn page_admin_update_user(
+    form: &Form,
+    current: Option<&User>,
+    id: i64,
+    csrf: &str,
+) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let user = User::get_by_sqlid(&db, id)?;
+
+    if current.map(|c| c.id) == Some(user.id) {
+        return Ok(
+            page_admin(Some("Cannot delete or demote yourself"), csrf)?.with_status_code(400)
+        );
+    }
+    match form.get("action").unwrap_or("") {
+        "delete" => user.delete(&db)?,
+        "admin" => user.set_admin(&db, true)?,
+        "unadmin" => user.set_admin(&db, false)?,
+        _ => return Ok(Response::text("Unknown action").with_status_code(400)),
+    }
+    Ok(Response::redirect_303("/admin"))
+}
+
+fn page_admin_save_config(form: &Form, csrf: &str) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+
+    let current = std::fs::read_to_string(cfg.config_filepath()).unwrap_or_default();
+    let data = match Config::merge_web_edit(&current, form.get("config").unwrap_or("")) {
+        Ok(data) => data,
+        Err(e) => {
+            return Ok(
+                page_admin(Some(&format!("Config not saved - {}", e)), csrf)?.with_status_code(400),
+            )
+        }
+    };
+    std::fs::write(cfg.config_filepath(), data)?;
+    info!("Saved {:?}", cfg.config_filepath());
+    page_admin(Some("Config saved"), csrf)
+}
+
+/// Add channels from an uploaded OPML file, reporting any entries which could not be resolved
+fn page_admin_import_opml(form: &Form, csrf: &str) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+
+    match crate::opml::import(&db, form.get("opml").unwrap_or("")) {
+        Ok(report) => page_admin(Some(&report.summary()), csrf),
+        Err(e) => {
+            Ok(page_admin(Some(&format!("Import failed - {:#}", e)), csrf)?.with_status_code(400))
+        }
+    }
+}
+
+fn page_admin_export_opml() -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+
+    let data = crate::opml::render(&crate::db::list_channels(&db)?)?;
+    Ok(Response::from_data("text/x-opml", data)
+        .with_unique_header("Content-Disposition", "attachment; filename=\"vidl.opml\""))
+}
+
+/// Names used for video statuses in the video list query string
+const STATUS_NAMES: &[(&str, VideoStatus)] = &[
+    ("new", VideoStatus::New),
+    ("queued", VideoStatus::Queued),
+    ("downloading", VideoStatus::Downloading),
+    ("grabbed", VideoStatus::Grabbed),
+    ("graberror", VideoStatus::GrabError),
+    ("ignore", VideoStatus::Ignore),
+];
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+/// Filtering, ordering and page size of the video list, given in the query string
+#[derive(Debug, PartialEq)]
+struct VideoListQuery {
+    filter: VideoFilter,
+    per_page: i64,
+}
+
+fn parse_date(date: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let d = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    Some(chrono::Utc.from_utc_datetime(&d.and_hms_opt(0, 0, 0)?))
+}
+
+impl VideoListQuery {
+    /// Parse from query parameters, ignoring any invalid values. `until` is inclusive of the
+    /// given day
+    fn parse(param: impl Fn(&str) -> Option<String>) -> VideoListQuery {
+        let statuses = param("status")
+            .map(|s| {
+                s.split(',')
+                    .filter_map(|name| STATUS_NAMES.iter().find(|(n, _)| *n == name))
+                    .map(|(_, st)| *st)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let filter = VideoFilter {
+            statuses,
+            since: param("since").and_then(|d| parse_date(&d)),
+            until: param("until")
+                .and_then(|d| parse_date(&d))
+                .map(|d| d + chrono::Duration::days(1)),
+            sort: param("sort")
+                .and_then(|s| VideoSort::from_str(&s).ok())
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+        let per_page = param("per_page")
+            .and_then(|x| x.parse::<i64>().ok())
+            .map(|x| x.clamp(1, MAX_PAGE_SIZE))
+            .unwrap_or(DEFAULT_PAGE_SIZE);
+        VideoListQuery { filter, per_page }
+    }
+
+    fn status(&self) -> String {
+        let names: Vec<&str> = self
+            .filter
+            .statuses
+            .iter()
+            .filter_map(|st| STATUS_NAMES.iter().find(|(_, s)| s == st))
+            .map(|(n, _)| *n)
+            .collect();
+        names.join(",")
+    }
+
+    /// Whether the filter is for exactly the named status
+    fn is_status(&self, name: &str) -> bool {
+        self.status() == name
+    }
+
+    fn since(&self) -> String {
+        self.filter
+            .since
+            .map(|d| d.format("%Y-%m-%d").to_string())
+            .unwrap_or_default()
+    }
+
+    fn until(&self) -> String {
+        self.filter
+            .until
+            .map(|d| {
+                (d - chrono::Duration::days(1))
+                    .format("%Y-%m-%d")
+                    .to_string()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Query string reproducing these options, for links to other pages. Only contains values
+    /// produced from validated input, so needs no escaping
+    fn to_query_string(&self) -> String {
+        let mut parts = vec![];
+        for (k, v) in &[
+            ("status", self.status()),
+            ("since", self.since()),
+            ("until", self.until()),
+        ] {
+            if !v.is_empty() {
+                parts.push(format!("{}={}", k, v));
+            }
+        }
+        if self.filter.sort != VideoSort::default() {
+            parts.push(format!("sort={}", self.filter.sort.as_str()));
+        }
+        if self.per_page != DEFAULT_PAGE_SIZE {
+            parts.push(format!("per_page={}", self.per_page));
+        }
+        parts.join("&")
+    }
+}
+
 #[derive(Template)]
 #[template(path = "video_list.html")]
 struct VideoListTemplate<'a> {
     videos: &'a WebChannelVideos<'a>,
     page: i64,
+    has_more: bool,
+    profiles: Vec<&'a String>,
+    query: &'a VideoListQuery,
+    status_names: Vec<&'static str>,
+    csrf: &'a str,
 }
 
-fn page_list_videos(id: Option<i64>, page: i64) -> Result<Response> {
-    let cfg = crate::config::Config::load();
+fn page_list_videos(
+    request: &Request,
+    user: Option<&User>,
+    id: Option<i64>,
+    csrf: &str,
+) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
     let db = crate::db::Database::open(&cfg)?;
-    let (c, videos): (Option<Channel>, Vec<DBVideoInfo>) = if let Some(id) = id {
+
+    let page: i64 = request
+        .get_param("page")
+        .and_then(|x| x.parse::<i64>().ok())
+        .unwrap_or(0)
+        .max(0);
+    let mut query = VideoListQuery::parse(|k| request.get_param(k));
+    if let Some(u) = user {
+        query.filter.ignored_by = Some(u.id);
+        if id.is_none() {
+            query.filter.subscribed_by = Some(u.id);
+        }
+    }
+
+    let (c, mut videos): (Option<Channel>, Vec<DBVideoInfo>) = if let Some(id) = id {
         let c = crate::db::Channel::get_by_sqlid(&db, id)?;
-        let videos = c.all_videos(&db, 50, page)?;
+        let videos = c.all_videos(&db, &query.filter, query.per_page, page)?;
         (Some(c), videos)
     } else {
-        let videos = crate::db::all_videos(&db, 50, page)?;
+        let videos = crate::db::all_videos(&db, &query.filter, query.per_page, page)?;
         (None, videos)
     };
+    let has_more = videos.len() as i64 == query.per_page;
+    if let Some(u) = user {
+        u.apply_state(&db, &mut videos)?;
+    }
 
     // Construct a map of WebChannel's to be referenced by each video
     let mut chans: HashMap<i64, WebChannel> = HashMap::new();
@@ -204,39 +546,521 @@ fn page_list_videos(id: Option<i64>, page: i64) -> Result<Response> {
 
     let t = VideoListTemplate {
         videos: &ret,
-        page: page,
+        page,
+        has_more,
+        profiles: cfg.profiles.keys().collect(),
+        query: &query,
+        status_names: STATUS_NAMES.iter().map(|(n, _)| *n).collect(),
+        csrf,
     };
     let html = t.render()?;
     Ok(Response::html(html))
 }
 
-fn page_download_video(videoid: i64, workers: Arc<Mutex<WorkerPool>>) -> Result<Response> {
-    let cfg = crate::config::Config::load();
+fn page_download_video(
+    videoid: i64,
+    profile: Option<String>,
+    workers: Arc<Mutex<WorkerPool>>,
+) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
     let db = crate::db::Database::open(&cfg)?;
     let v = crate::db::DBVideoInfo::get_by_sqlid(&db, videoid)?;
 
+    // Store requested quality profile, with none meaning the channel's profile is used
+    if let Some(ref p) = profile {
+        cfg.profile(p)?;
+    }
+    v.set_profile(&db, profile.as_deref())?;
+
     // Mark video as queued
     v.set_status(&db, VideoStatus::Queued)?;
 
     // Then add it to the work queue
     {
-        let w = workers.lock().unwrap();
+        let w = lock(&workers);
         w.enqueue(crate::worker::WorkItem::Download(v));
     }
     Ok(Response::text("cool"))
 }
 
+fn page_cancel_download(videoid: i64, cleanup: bool) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let v = crate::db::DBVideoInfo::get_by_sqlid(&db, videoid)?;
+
+    crate::download::cancel(&db, &cfg, &v, cleanup)?;
+    Ok(Response::text("cancelled"))
+}
+
+/// Send the browser back to the page an action was triggered from
+fn redirect_back(request: &Request) -> Response {
+    let to = request.header("Referer").unwrap_or("/").to_string();
+    Response::redirect_303(to)
+}
+
+/// Action applied to several videos selected in the video list
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BulkAction {
+    Download,
+    Ignore,
+    Reset,
+    Retry,
+}
+
+impl BulkAction {
+    fn from_str(action: &str) -> Option<BulkAction> {
+        match action {
+            "download" => Some(BulkAction::Download),
+            "ignore" => Some(BulkAction::Ignore),
+            "reset" => Some(BulkAction::Reset),
+            "retry" => Some(BulkAction::Retry),
+            _ => None,
+        }
+    }
+
+    /// Whether the action should change a video with the given status. Videos being
+    /// downloaded are never touched, they must be cancelled first
+    fn applies_to(&self, status: VideoStatus) -> bool {
+        matches!(
+            (self, status),
+            (BulkAction::Download, VideoStatus::New)
+                | (BulkAction::Download, VideoStatus::GrabError)
+                | (BulkAction::Download, VideoStatus::Ignore)
+                | (BulkAction::Ignore, VideoStatus::New)
+                | (BulkAction::Ignore, VideoStatus::GrabError)
+                | (BulkAction::Reset, VideoStatus::Ignore)
+                | (BulkAction::Reset, VideoStatus::GrabError)
+                | (BulkAction::Retry, VideoStatus::GrabError)
+        )
+    }
+
+    fn new_status(&self) -> VideoStatus {
+        match self {
+            BulkAction::Download | BulkAction::Retry => VideoStatus::Queued,
+            BulkAction::Ignore => VideoStatus::Ignore,
+            BulkAction::Reset => VideoStatus::New,
+        }
+    }
+}
+
+fn page_bulk_action(
+    request: &Request,
+    form: &Form,
+    user: Option<&User>,
+    workers: Arc<Mutex<WorkerPool>>,
+) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+
+    let ids: Vec<i64> = form
+        .get_all("video")
+        .filter_map(|v| v.parse::<i64>().ok())
+        .collect();
+    let action = match form.get("action").and_then(BulkAction::from_str) {
+        Some(a) => a,
+        None => return Ok(Response::text("Unknown action").with_status_code(400)),
+    };
+
+    let mut videos = vec![];
+    for id in ids {
+        videos.push(DBVideoInfo::get_by_sqlid(&db, id)?);
+    }
+    let real_status: Vec<VideoStatus> = videos.iter().map(|v| v.status).collect();
+    if let Some(u) = user {
+        u.apply_state(&db, &mut videos)?;
+    }
+
+    // With accounts, ignoring only hides videos from the current user. Everything else changes
+    // the shared status, as downloads are shared between users
+    let mut global: Vec<DBVideoInfo> = vec![];
+    for (v, real) in videos.into_iter().zip(real_status) {
+        if !action.applies_to(v.status) {
+            continue;
+        }
+        match user {
+            Some(u) if action == BulkAction::Ignore => {
+                u.set_ignored(&db, &v, true)?;
+                continue;
+            }
+            Some(u) if v.status == VideoStatus::Ignore && real != VideoStatus::Ignore => {
+                u.set_ignored(&db, &v, false)?;
+                if action == BulkAction::Reset {
+                    continue;
+                }
+            }
+            _ => (),
+        }
+        global.push(v);
+    }
+    let videos = global;
+    let ids: Vec<i64> = videos.iter().map(|v| v.id).collect();
+    info!("Applying {:?} to {} videos", action, ids.len());
+    crate::db::set_status_many(&db, &ids, action.new_status())?;
+
+    if action.new_status() == VideoStatus::Queued {
+        let w = lock(&workers);
+        for v in videos {
+            w.enqueue(crate::worker::WorkItem::Download(v));
+        }
+    }
+    Ok(redirect_back(request))
+}
+
+fn page_ignore_video(request: &Request, user: Option<&User>, videoid: i64) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let v = DBVideoInfo::get_by_sqlid(&db, videoid)?;
+    if BulkAction::Ignore.applies_to(v.status) {
+        match user {
+            Some(u) => u.set_ignored(&db, &v, true)?,
+            None => v.set_status(&db, VideoStatus::Ignore)?,
+        }
+    }
+    Ok(redirect_back(request))
+}
+
+fn page_ignore_older(request: &Request, user: Option<&User>, videoid: i64) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let v = DBVideoInfo::get_by_sqlid(&db, videoid)?;
+    let chan = v.channel(&db)?;
+    let changed = match user {
+        Some(u) => u.ignore_older_than(&db, &chan, &v.info.published_at)?,
+        None => chan.ignore_older_than(&db, &v.info.published_at)?,
+    };
+    info!(
+        "Ignored {} videos in {:?} older than {:?}",
+        changed, &chan, &v.info
+    );
+    Ok(redirect_back(request))
+}
+
+fn page_set_watched(
+    request: &Request,
+    user: Option<&User>,
+    videoid: i64,
+    watched: bool,
+) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let v = DBVideoInfo::get_by_sqlid(&db, videoid)?;
+    match user {
+        Some(u) => u.set_watched(&db, &v, watched)?,
+        None => v.set_watched(&db, watched)?,
+    }
+    Ok(redirect_back(request))
+}
+
+/// Playback progress reported by the player, marking the video watched once it finishes
+fn page_watch_progress(form: &Form, user: Option<&User>, videoid: i64) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let v = DBVideoInfo::get_by_sqlid(&db, videoid)?;
+
+    let position = form
+        .get("position")
+        .and_then(|p| p.parse::<f64>().ok())
+        .filter(|p| p.is_finite());
+    let finished = form.get("finished") == Some("1");
+
+    match (finished, position, user) {
+        (true, _, Some(u)) => u.set_watched(&db, &v, true)?,
+        (true, _, None) => v.set_watched(&db, true)?,
+        (false, Some(p), Some(u)) => u.set_watch_position(&db, &v, p.max(0.0))?,
+        (false, Some(p), None) => v.set_watch_position(&db, p.max(0.0))?,
+        (false, None, _) => {
+            return Ok(Response::text("Missing position").with_status_code(400));
+        }
+    }
+    Ok(Response::empty_204())
+}
+
+#[derive(Template)]
+#[template(path = "video_player.html")]
+struct VideoPlayerTemplate<'a> {
+    video: &'a WebVideoInfo<'a>,
+    csrf: &'a str,
+}
+
+fn page_play_video(user: Option<&User>, videoid: i64, csrf: &str) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let mut v = vec![crate::db::DBVideoInfo::get_by_sqlid(&db, videoid)?];
+    if let Some(u) = user {
+        u.apply_state(&db, &mut v)?;
+    }
+    let v = v.remove(0);
+    let chan: WebChannel = v.channel(&db)?.into();
+    let video: WebVideoInfo = (v, &chan).into();
+
+    let t = VideoPlayerTemplate {
+        video: &video,
+        csrf,
+    };
+    let html = t.render()?;
+    Ok(Response::html(html))
+}
+
+#[derive(Template)]
+#[template(path = "queue.html")]
+struct QueueTemplate<'a> {
+    queue: &'a crate::worker::QueueReport,
+    health: &'a crate::worker::PoolHealth,
+}
+
+fn page_queue(workers: Arc<Mutex<WorkerPool>>) -> Result<Response> {
+    let (queue, health) = {
+        let w = lock(&workers);
+        (w.queue(), w.health())
+    };
+    let t = QueueTemplate {
+        queue: &queue,
+        health: &health,
+    };
+    Ok(Response::html(t.render()?))
+}
+
+/// Metrics in Prometheus text format
+fn page_metrics(workers: Arc<Mutex<WorkerPool>>) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let queue = lock(&workers).queue();
+    let text = crate::metrics::render(&db, &queue)?;
+    Ok(Response::from_data("text/plain; version=0.0.4", text))
+}
+
+/// Whether vidl can serve requests and download videos, with 503 status if not
+fn page_readyz(workers: Arc<Mutex<WorkerPool>>) -> Response {
+    let health = lock(&workers).health();
+    let readiness = crate::health::check(&health);
+    for p in readiness.probes.iter().filter(|p| !p.ok) {
+        warn!("Readiness probe {} failed - {}", p.name, p.detail);
+    }
+    let status = if readiness.ready { 200 } else { 503 };
+    Response::json(&readiness).with_status_code(status)
+}
+
+/// Parse the value of a HTTP `Range` header into an inclusive `(start, end)` byte range for a
+/// file of `len` bytes. Only a single range in bytes is supported, returning `None` for
+/// anything else or if the range cannot be satisfied
+fn parse_range(header: &str, len: u64) -> Option<(u64, u64)> {
+    let header = header.trim();
+    if !header.starts_with("bytes=") || header.contains(',') || len == 0 {
+        return None;
+    }
+    let mut parts = header["bytes=".len()..].splitn(2, '-');
+    let start = parts.next()?.trim();
+    let end = parts.next()?.trim();
+
+    if start.is_empty() {
+        // Suffix range, e.g "bytes=-500" is the last 500 bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 {
+            return None;
+        }
+        return Some((len.saturating_sub(suffix), len - 1));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = if end.is_empty() {
+        len - 1
+    } else {
+        end.parse::<u64>().ok()?.min(len - 1)
+    };
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+fn page_video_file(request: &Request, videoid: i64) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let v = crate::db::DBVideoInfo::get_by_sqlid(&db, videoid)?;
+
+    let path = match v.file_path {
+        Some(ref p) if Path::new(p).exists() => Path::new(p),
+        _ => return Ok(Response::text("404 No downloaded file").with_status_code(404)),
+    };
+
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let content_type = mime_for_path(path);
+
+    let range = match request.header("Range") {
+        // No range requested, serve entire file
+        None => {
+            return Ok(Response::from_file(content_type, file)
+                .with_unique_header("Accept-Ranges", "bytes"))
+        }
+        Some(r) => parse_range(r, len),
+    };
+
+    match range {
+        None => Ok(Response::text("416 Range Not Satisfiable")
+            .with_status_code(416)
+            .with_unique_header("Content-Range", format!("bytes */{}", len))),
+        Some((start, end)) => {
+            let size = end - start + 1;
+            file.seek(SeekFrom::Start(start))?;
+            let mut resp = Response::from_data(content_type, vec![])
+                .with_status_code(206)
+                .with_unique_header("Accept-Ranges", "bytes")
+                .with_unique_header("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+            resp.data = ResponseBody::from_reader_and_size(file.take(size), size as usize);
+            Ok(resp)
+        }
+    }
+}
+
+/// Entry in a podcast feed
+struct FeedItem {
+    title: String,
+    description: String,
+    link: String,
+    guid: String,
+    pub_date: String,
+    image: String,
+    enclosure_url: String,
+    enclosure_length: u64,
+    enclosure_type: &'static str,
+}
+
+#[derive(Template)]
+#[template(path = "feed.xml")]
+struct FeedTemplate<'a> {
+    title: &'a str,
+    link: &'a str,
+    description: &'a str,
+    image: &'a str,
+    items: &'a [FeedItem],
+}
+
+/// Percent-encode a value for use in a URL query string
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// RSS podcast feed of downloaded videos for a channel (or all channels if `None`). If
+/// `audio_only` is set, only files with an audio MIME type are included. The feed of all
+/// channels for a logged in user only includes the channels they are subscribed to
+fn page_feed(
+    request: &Request,
+    user: Option<&User>,
+    chanid: Option<i64>,
+    audio_only: bool,
+) -> Result<Response> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+
+    // Absolute URL's are required for enclosures, so use whatever host the client connected to
+    let base_url = format!(
+        "http://{}",
+        request
+            .header("Host")
+            .map(|h| h.to_string())
+            .unwrap_or_else(|| format!("{}:{}", cfg.web_host, cfg.web_port))
+    );
+
+    let chan = match chanid {
+        Some(id) => Some(crate::db::Channel::get_by_sqlid(&db, id)?),
+        None => None,
+    };
+
+    // Feed readers authenticating with a token need it to fetch the files too
+    let file_query = match request.get_param("token") {
+        Some(t) => format!("?token={}", url_encode(&t)),
+        None => "".into(),
+    };
+
+    let mut items: Vec<FeedItem> = vec![];
+    let subscriber = user.filter(|_| chanid.is_none()).map(|u| u.id);
+    for v in crate::db::grabbed_videos(&db, chanid, subscriber, 100)? {
+        let path = match v.file_path {
+            Some(ref p) => Path::new(p),
+            None => continue,
+        };
+        let enclosure_length = match std::fs::metadata(path) {
+            Ok(m) => m.len(),
+            // File has gone missing
+            Err(_) => continue,
+        };
+        let enclosure_type = mime_for_path(path);
+        if audio_only && !enclosure_type.starts_with("audio/") {
+            continue;
+        }
+
+        items.push(FeedItem {
+            enclosure_url: format!("{}/video/{}/file{}", base_url, v.id, file_query),
+            enclosure_length,
+            enclosure_type,
+            title: v.info.title,
+            description: v.info.description,
+            link: v.info.url.clone(),
+            guid: v.info.url,
+            pub_date: v.info.published_at.to_rfc2822(),
+            image: v.info.thumbnail_url,
+        });
+    }
+
+    let (title, link, description, image) = match chan {
+        Some(c) => (
+            c.title.clone(),
+            format!("{}/channel/{}", base_url, c.id),
+            format!("Videos downloaded from {} by vidl", c.title),
+            c.thumbnail,
+        ),
+        None => (
+            "vidl - all channels".to_string(),
+            format!("{}/channel/_all", base_url),
+            "Videos downloaded by vidl".to_string(),
+            "".to_string(),
+        ),
+    };
+
+    let t = FeedTemplate {
+        title: &title,
+        link: &link,
+        description: &description,
+        image: &image,
+        items: &items,
+    };
+    let xml = t.render()?;
+    Ok(Response::from_data("application/rss+xml", xml))
+}
+
+/// Parse the name of a feed file like `123.xml` or `all.xml` into a channel ID, with `None`
+/// meaning all channels
+fn parse_feed_name(name: &str) -> Option<Option<i64>> {
+    if !name.ends_with(".xml") {
+        return None;
+    }
+    match &name[..name.len() - ".xml".len()] {
+        "all" => Some(None),
+        id => id.parse::<i64>().ok().map(Some),
+    }
+}
+
 enum ThumbnailType {
     Video,
     Channel,
 }
 
 fn page_thumbnail(
+    request: &Request,
     id: i64,
     what: ThumbnailType,
     workers: Arc<Mutex<WorkerPool>>,
 ) -> Result<Response> {
-    let cfg = crate::config::Config::load();
+    let cfg = crate::config::Config::load()?;
     let db = crate::db::Database::open(&cfg)?;
 
     let url = match what {
@@ -250,17 +1074,63 @@ fn page_thumbnail(
         }
     };
 
-    let image = {
-        let mut ic = IMG_CACHE.lock().unwrap();
-        let image = ic.get(url, workers)?;
-        image.clone()
-    };
-    match image {
-        ImageCacheResponse::Redirect(url) => Ok(Response::redirect_303(url)),
-        ImageCacheResponse::Image(image) => Ok(Response::from_data(
-            image.content_type.clone(),
-            image.data.clone(),
-        )),
+    match crate::thumbnails::get(&url)? {
+        Some(t) => {
+            if not_modified(request, &t) {
+                return Ok(Response::empty_204()
+                    .with_status_code(304)
+                    .with_unique_header("ETag", t.etag));
+            }
+            let last_modified = t.fetched_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+            Ok(Response::from_data(t.content_type, t.data)
+                .with_unique_header("ETag", t.etag)
+                .with_unique_header("Last-Modified", last_modified))
+        }
+        None if crate::thumbnails::unavailable(&url) => Ok(placeholder_image()),
+        None => {
+            // Fetch in background for next time, sending browser to the original for now
+            let w = lock(&workers);
+            w.enqueue(crate::worker::WorkItem::ThumbnailCache(url.clone()));
+            Ok(Response::redirect_303(url))
+        }
+    }
+}
+
+/// Image shown in place of thumbnails which could not be fetched
+fn placeholder_image() -> Response {
+    Response::from_data("image/svg+xml", include_str!("../static/placeholder.svg"))
+}
+
+/// Whether the browser's copy of a thumbnail is current, from the `If-None-Match` or (if not
+/// present) `If-Modified-Since` headers
+fn not_modified(request: &Request, t: &crate::thumbnails::Thumbnail) -> bool {
+    if let Some(etags) = request.header("If-None-Match") {
+        return etags
+            .split(',')
+            .any(|e| e.trim() == t.etag || e.trim() == "*");
+    }
+    match request
+        .header("If-Modified-Since")
+        .and_then(|d| chrono::DateTime::parse_from_rfc2822(d).ok())
+    {
+        Some(since) => t.fetched_at.timestamp() <= since.timestamp(),
+        None => false,
+    }
+}
+
+/// Response to a request without valid credentials. Browsers are sent to the login form if
+/// there are user accounts, everything else gets a 401
+fn unauthorized(request: &Request, cfg: &Config, accounts: bool) -> Response {
+    let wants_html = request
+        .header("Accept")
+        .map(|a| a.contains("text/html"))
+        .unwrap_or(false);
+    if accounts && wants_html && request.method() == "GET" {
+        Response::redirect_303("/login")
+    } else if cfg.web_auth.username.is_some() {
+        Response::basic_http_auth_login_required("vidl")
+    } else {
+        Response::text("401 Unauthorized").with_status_code(401)
     }
 }
 
@@ -275,6 +1145,9 @@ fn handle_response(request: &Request, workers: Arc<Mutex<WorkerPool>>) -> Respon
                 "application/javascript",
             )),
             "/pure-min.css" => Some((include_str!("../static/pure-min.css"), "text/css")),
+            "/placeholder.svg" => {
+                Some((include_str!("../static/placeholder.svg"), "image/svg+xml"))
+            }
             "/tippy_6.js" => Some((
                 include_str!("../static/tippy_6.js"),
                 "application/javascript",
@@ -287,48 +1160,210 @@ fn handle_response(request: &Request, workers: Arc<Mutex<WorkerPool>>) -> Respon
         };
     }
 
+    // Probes for container orchestrators, which cannot log in
+    match request.url().as_ref() {
+        "/healthz" => return Response::text("ok"),
+        "/readyz" => return page_readyz(workers),
+        _ => (),
+    }
+
+    let loaded = Config::load().and_then(|cfg| {
+        let db = crate::db::Database::open(&cfg)?;
+        let auth = crate::auth::authenticate(request, &cfg, &db)?;
+        Ok((cfg, auth))
+    });
+    let (cfg, auth) = match loaded {
+        Ok(a) => a,
+        Err(e) => {
+            return Response::text(format!("Internal service error: {:?}", e)).with_status_code(500)
+        }
+    };
+    if let Auth::Anonymous { accounts } = auth {
+        // Only the login form is available without logging in
+        if !(accounts && request.url() == "/login") {
+            return unauthorized(request, &cfg, accounts);
+        }
+    }
+    let user = auth.user();
+    if request.url().starts_with("/admin") && !auth.is_admin() {
+        return Response::text(
+            "403 Forbidden - admin pages need an admin user (see `vidl user add`) or web \
+             authentication configured",
+        )
+        .with_status_code(403);
+    }
+
+    let form = match Form::read(request) {
+        Ok(f) => f,
+        Err(e) => return Response::text(format!("Invalid form - {}", e)).with_status_code(400),
+    };
+    // Browsers send cookies and basic auth along with requests triggered by other sites, so
+    // anything changing state must prove it came from a vidl page. API tokens are never sent
+    // automatically, so requests authenticated by one are exempt
+    if request.method() == "POST"
+        && !crate::auth::csrf_exempt(
+            &auth,
+            &cfg.web_auth,
+            crate::auth::api_token(request).as_deref(),
+        )
+        && !crate::auth::check_csrf(request, form.get(crate::auth::CSRF_FIELD))
+    {
+        return Response::text("403 Forbidden - missing or invalid CSRF token")
+            .with_status_code(403);
+    }
+    let (csrf, new_csrf) = match crate::auth::csrf_cookie(request) {
+        Some(c) => (c, false),
+        None => match crate::users::random_token() {
+            Ok(c) => (c, true),
+            Err(e) => {
+                return Response::text(format!("Internal service error: {:?}", e))
+                    .with_status_code(500)
+            }
+        },
+    };
+
     let resp: Result<Response> = router!(request,
         (GET) ["/"] => {
-            page_chan_list()
+            page_chan_list(user, auth.is_admin(), &csrf)
+        },
+        (GET) ["/login"] => {
+            page_login_form(None, &csrf)
+        },
+        (POST) ["/login"] => {
+            page_login(&form, &csrf)
+        },
+        (POST) ["/logout"] => {
+            page_logout(request)
+        },
+        (GET) ["/admin"] => {
+            page_admin(None, &csrf)
+        },
+        (POST) ["/admin/users"] => {
+            page_admin_create_user(&form, &csrf)
+        },
+        (POST) ["/admin/users/{id}", id: i64] => {
+            page_admin_update_user(&form, user, id, &csrf)
+        },
+        (POST) ["/admin/config"] => {
+            page_admin_save_config(&form, &csrf)
+        },
+        (GET) ["/admin/opml"] => {
+            page_admin_export_opml()
+        },
+        (POST) ["/admin/opml"] => {
+            page_admin_import_opml(&form, &csrf)
         },
         (GET) ["/channel/_all"] => {
-            let page: i64 = request.get_param("page").and_then(|x| x.parse::<i64>().ok()).unwrap_or(0);
-            page_list_videos(None, page)
+            page_list_videos(request, user, None, &csrf)
         },
         (GET) ["/channel/{chanid}", chanid: i64] => {
-            let page: i64 = request.get_param("page").and_then(|x| x.parse::<i64>().ok()).unwrap_or(0);
-            page_list_videos(Some(chanid), page)
+            page_list_videos(request, user, Some(chanid), &csrf)
+        },
+        (POST) ["/channel/{chanid}/subscribe", chanid: i64] => {
+            page_subscribe(request, user, chanid, true)
+        },
+        (POST) ["/channel/{chanid}/unsubscribe", chanid: i64] => {
+            page_subscribe(request, user, chanid, false)
+        },
+        (POST) ["/download/{videoid}", videoid: i64] => {
+            let profile = form.get("profile").filter(|p| !p.is_empty()).map(|p| p.to_string());
+            page_download_video(videoid, profile, workers.clone())
+        },
+        (POST) ["/download/{videoid}/cancel", videoid: i64] => {
+            let cleanup = form.get("cleanup") == Some("1");
+            page_cancel_download(videoid, cleanup)
+        },
+        (POST) ["/videos/bulk"] => {
+            page_bulk_action(request, &form, user, workers.clone())
+        },
+        (POST) ["/video/{videoid}/ignore", videoid: i64] => {
+            page_ignore_video(request, user, videoid)
+        },
+        (POST) ["/video/{videoid}/ignore-older", videoid: i64] => {
+            page_ignore_older(request, user, videoid)
+        },
+        (POST) ["/video/{videoid}/watched", videoid: i64] => {
+            let watched = form.get("watched") != Some("0");
+            page_set_watched(request, user, videoid, watched)
+        },
+        (POST) ["/video/{videoid}/progress", videoid: i64] => {
+            page_watch_progress(&form, user, videoid)
+        },
+        (GET) ["/video/{videoid}/play", videoid: i64] => {
+            page_play_video(user, videoid, &csrf)
+        },
+        (GET) ["/video/{videoid}/file", videoid: i64] => {
+            page_video_file(request, videoid)
+        },
+        (GET) ["/feed/{name}", name: String] => {
+            match parse_feed_name(&name) {
+                Some(chanid) => page_feed(request, user, chanid, false),
+                None => Ok(Response::text("404 Not found").with_status_code(404)),
+            }
+        },
+        (GET) ["/feed/audio/{name}", name: String] => {
+            match parse_feed_name(&name) {
+                Some(chanid) => page_feed(request, user, chanid, true),
+                None => Ok(Response::text("404 Not found").with_status_code(404)),
+            }
+        },
+        (GET) ["/workers"] => {
+            Ok(Response::json(&lock(&workers).health()))
         },
-        (GET) ["/download/{videoid}", videoid: i64] => {
-            page_download_video(videoid, workers.clone())
+        (GET) ["/metrics"] => {
+            page_metrics(workers.clone())
+        },
+        (GET) ["/queue"] => {
+            page_queue(workers.clone())
+        },
+        (GET) ["/queue.json"] => {
+            Ok(Response::json(&lock(&workers).queue()))
         },
         (GET) ["/thumbnail/video/{id}", id: i64] => {
-            page_thumbnail(id, ThumbnailType::Video, workers.clone())
+            page_thumbnail(request, id, ThumbnailType::Video, workers.clone())
         },
         (GET) ["/thumbnail/channel/{id}", id: i64] => {
-            page_thumbnail(id, ThumbnailType::Channel, workers.clone())
+            page_thumbnail(request, id, ThumbnailType::Channel, workers.clone())
         },
         // Default route
         _ => {
             Ok(Response::text("404 Not found").with_status_code(404))
         }
     );
-    match resp {
+    let resp = match resp {
         Ok(r) => r,
-        Err(e) => Response::text(&format!("Internal service error: {:?}", e)).with_status_code(500),
+        Err(e) => Response::text(format!("Internal service error: {:?}", e)).with_status_code(500),
+    };
+    if new_csrf {
+        resp.with_additional_header(
+            "Set-Cookie",
+            format!(
+                "{}={}; Path=/; HttpOnly; SameSite=Strict",
+                crate::auth::CSRF_COOKIE,
+                csrf
+            ),
+        )
+    } else {
+        resp
     }
 }
 
 fn serve(workers: Arc<Mutex<WorkerPool>>) -> Result<()> {
-    let cfg = Config::load();
+    let cfg = Config::load()?;
 
     println!("yep");
     let addr = format!("{}:{}", cfg.web_host, cfg.web_port);
     let url = format!("http://{}", &addr);
     info!("Listening on {}", &url);
-    let _p = std::process::Command::new("terminal-notifier")
-        .args(&["-message", "web server started", "-open", &url])
-        .spawn();
+    crate::notify::notify(
+        &cfg,
+        &crate::notify::Event {
+            kind: crate::notify::EventKind::ServerStarted,
+            summary: "vidl web server started".into(),
+            body: format!("Listening on {}", &url),
+            url: Some(url.clone()),
+        },
+    );
     let srv = rouille::Server::new(&addr, move |request| {
         handle_response(request, workers.clone())
     })
@@ -353,3 +1388,81 @@ pub fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_url_encode() {
+        assert_eq!(url_encode("abc-123_x.y~"), "abc-123_x.y~");
+        assert_eq!(url_encode("a&b#c+d e/é"), "a%26b%23c%2Bd%20e%2F%C3%A9");
+    }
+
+    #[test]
+    fn test_bulk_action() {
+        assert_eq!(BulkAction::from_str("retry"), Some(BulkAction::Retry));
+        assert_eq!(BulkAction::from_str("delete"), None);
+
+        assert!(BulkAction::Download.applies_to(VideoStatus::Ignore));
+        assert!(!BulkAction::Download.applies_to(VideoStatus::Grabbed));
+        assert!(!BulkAction::Ignore.applies_to(VideoStatus::Downloading));
+        assert!(BulkAction::Reset.applies_to(VideoStatus::GrabError));
+        assert!(!BulkAction::Retry.applies_to(VideoStatus::New));
+        assert_eq!(BulkAction::Retry.new_status(), VideoStatus::Queued);
+    }
+
+    #[test]
+    fn test_video_list_query() {
+        let params: HashMap<&str, &str> = [
+            ("status", "new,graberror,bogus"),
+            ("since", "2020-01-01"),
+            ("until", "2020-01-31"),
+            ("sort", "oldest"),
+            ("per_page", "100000"),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let q = VideoListQuery::parse(|k| params.get(k).map(|v| v.to_string()));
+        assert_eq!(
+            q.filter.statuses,
+            vec![VideoStatus::New, VideoStatus::GrabError]
+        );
+        assert_eq!(q.filter.until, parse_date("2020-02-01"));
+        assert_eq!(q.per_page, MAX_PAGE_SIZE);
+        assert_eq!(
+            q.to_query_string(),
+            "status=new,graberror&since=2020-01-01&until=2020-01-31&sort=oldest&per_page=500"
+        );
+
+        // Defaults are omitted so unfiltered links stay clean
+        let q = VideoListQuery::parse(|_| None);
+        assert_eq!(q.filter, VideoFilter::default());
+        assert_eq!(q.to_query_string(), "");
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range("bytes=500-", 1000), Some((500, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some((0, 999)));
+
+        // Unsatisfiable or unsupported
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=50-10", 1000), None);
+        assert_eq!(parse_range("bytes=0-10,20-30", 1000), None);
+        assert_eq!(parse_range("lines=0-10", 1000), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+    }
+
+    #[test]
+    fn test_parse_feed_name() {
+        assert_eq!(parse_feed_name("all.xml"), Some(None));
+        assert_eq!(parse_feed_name("12.xml"), Some(Some(12)));
+        assert_eq!(parse_feed_name("12"), None);
+        assert_eq!(parse_feed_name("abc.xml"), None);
+    }
+}
@@ -1,11 +1,22 @@
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
-use crate::common::VideoStatus;
+use crate::common::{lock, VideoStatus};
+use crate::config::Config;
 use crate::db::{Channel, DBVideoInfo};
+use crate::download::{DownloadErrorKind, DownloadFailure};
+use crate::notify::{notify, Event};
+
+/// Exponential backoff, `base` delay doubling after each failed attempt (starting from 1)
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    base * 2u32.saturating_pow(attempt.saturating_sub(1).min(16))
+}
 
 pub enum WorkItem {
     Download(DBVideoInfo),
@@ -14,122 +25,532 @@ pub enum WorkItem {
     ThumbnailCache(String),
 }
 
+impl WorkItem {
+    fn kind(&self) -> &'static str {
+        match self {
+            WorkItem::Download(_) => "download",
+            WorkItem::Shutdown => "shutdown",
+            WorkItem::UpdateCheck(_) => "update-check",
+            WorkItem::ThumbnailCache(_) => "thumbnail",
+        }
+    }
+
+    /// Short description for logs and health reports
+    fn describe(&self) -> String {
+        match self {
+            WorkItem::Download(v) => format!("Download {:?}", v.info.title),
+            WorkItem::Shutdown => "Shutdown".into(),
+            WorkItem::UpdateCheck(c) => format!("Update check {:?}", c.title),
+            WorkItem::ThumbnailCache(url) => format!("Thumbnail {}", url),
+        }
+    }
+}
+
+/// Text of a panic, for recording as an error
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".into()
+    }
+}
+
+/// What a worker is doing, shared with the pool for health reports
+#[derive(Default)]
+struct WorkerStatus {
+    /// Description of current job and when it started, `None` if idle
+    current: Option<(String, Instant)>,
+    jobs_done: u64,
+    jobs_failed: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct WorkerHealth {
+    pub num: usize,
+    /// Description of current job, `None` if idle
+    pub job: Option<String>,
+    pub busy_secs: Option<u64>,
+    pub jobs_done: u64,
+    pub jobs_failed: u64,
+}
+
+/// Snapshot of what the worker pool is doing
+#[derive(Serialize, Debug, Clone)]
+pub struct PoolHealth {
+    pub workers: Vec<WorkerHealth>,
+    pub active: usize,
+    pub idle: usize,
+    /// Worker threads which are running, fewer than `workers` if any have died
+    pub alive: usize,
+    /// How many times a worker thread died and was replaced
+    pub respawns: u64,
+}
+
+/// How many finished jobs are kept for the queue report
+const RECENT_JOBS: usize = 50;
+
+/// Work item as it passes through the queue
+#[derive(Clone)]
+struct Job {
+    id: u64,
+    kind: &'static str,
+    description: String,
+    queued_at: chrono::DateTime<chrono::Utc>,
+    started_at: Option<chrono::DateTime<chrono::Utc>>,
+    finished_at: Option<chrono::DateTime<chrono::Utc>>,
+    worker: Option<usize>,
+    error: Option<String>,
+}
+
+/// Pending, running and recently finished jobs
+#[derive(Default)]
+struct JobLog {
+    next_id: u64,
+    pending: VecDeque<Job>,
+    running: Vec<Job>,
+    finished: VecDeque<Job>,
+}
+
+impl JobLog {
+    fn queued(&mut self, item: &WorkItem) -> u64 {
+        self.next_id += 1;
+        self.pending.push_back(Job {
+            id: self.next_id,
+            kind: item.kind(),
+            description: item.describe(),
+            queued_at: chrono::Utc::now(),
+            started_at: None,
+            finished_at: None,
+            worker: None,
+            error: None,
+        });
+        self.next_id
+    }
+
+    fn started(&mut self, id: u64, worker: usize) {
+        if let Some(pos) = self.pending.iter().position(|j| j.id == id) {
+            let mut job = self.pending.remove(pos).unwrap();
+            job.started_at = Some(chrono::Utc::now());
+            job.worker = Some(worker);
+            self.running.push(job);
+        }
+    }
+
+    fn finished(&mut self, id: u64, error: Option<String>) {
+        if let Some(pos) = self.running.iter().position(|j| j.id == id) {
+            let mut job = self.running.remove(pos);
+            job.finished_at = Some(chrono::Utc::now());
+            job.error = error;
+            self.finished.push_front(job);
+            self.finished.truncate(RECENT_JOBS);
+        }
+    }
+}
+
+/// Job as shown in the queue report
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobReport {
+    pub id: u64,
+    /// One of `download`, `update-check` or `thumbnail`
+    pub kind: String,
+    pub description: String,
+    pub queued_at: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    /// Time spent waiting in the queue, up to now if not yet started
+    pub wait_secs: i64,
+    /// Time spent running, up to now if not yet finished
+    pub run_secs: Option<i64>,
+    pub worker: Option<usize>,
+    pub error: Option<String>,
+}
+
+impl From<&Job> for JobReport {
+    fn from(j: &Job) -> JobReport {
+        let now = chrono::Utc::now();
+        JobReport {
+            id: j.id,
+            kind: j.kind.into(),
+            description: j.description.clone(),
+            queued_at: j.queued_at.to_rfc3339(),
+            started_at: j.started_at.map(|t| t.to_rfc3339()),
+            finished_at: j.finished_at.map(|t| t.to_rfc3339()),
+            wait_secs: (j.started_at.unwrap_or(now) - j.queued_at).num_seconds(),
+            run_secs: j
+                .started_at
+                .map(|s| (j.finished_at.unwrap_or(now) - s).num_seconds()),
+            worker: j.worker,
+            error: j.error.clone(),
+        }
+    }
+}
+
+/// Snapshot of the work queue, most recently finished jobs first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueReport {
+    pub pending: Vec<JobReport>,
+    pub running: Vec<JobReport>,
+    pub finished: Vec<JobReport>,
+}
+
+/// State shared between the pool and its workers
+struct Shared {
+    recv: Mutex<mpsc::Receiver<(u64, WorkItem)>>,
+    /// Work items along with their ID in the job log
+    sender: Mutex<mpsc::Sender<(u64, WorkItem)>>,
+    statuses: Mutex<Vec<WorkerStatus>>,
+    jobs: Mutex<JobLog>,
+    respawns: Mutex<u64>,
+    pool: threadpool::ThreadPool,
+}
+
+impl Shared {
+    fn enqueue(&self, item: WorkItem) {
+        let id = match item {
+            WorkItem::Shutdown => 0,
+            _ => lock(&self.jobs).queued(&item),
+        };
+        if lock(&self.sender).send((id, item)).is_err() {
+            error!("Worker pool has stopped, work item dropped");
+        }
+    }
+}
+
 struct Worker {
-    recv: Arc<Mutex<mpsc::Receiver<WorkItem>>>,
+    shared: Arc<Shared>,
     num: usize,
 }
 
+/// Replaces a worker if its thread dies. Jobs are run with panics caught, so this only happens
+/// for a bug in the worker loop itself
+struct Sentinel<'a>(&'a Worker);
+
+impl<'a> Drop for Sentinel<'a> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            let Worker { shared, num } = self.0;
+            error!("Worker {} died, starting replacement", num);
+            *lock(&shared.respawns) += 1;
+            lock(&shared.statuses)[*num].current = None;
+            Worker::spawn(shared.clone(), *num);
+        }
+    }
+}
+
 impl Worker {
+    fn spawn(shared: Arc<Shared>, num: usize) {
+        let w = Worker {
+            shared: shared.clone(),
+            num,
+        };
+        shared.pool.execute(move || w.run());
+    }
+
     fn run(&self) {
+        let _sentinel = Sentinel(self);
         loop {
-            let m = self.recv.lock().unwrap().recv().unwrap();
-            match m {
-                WorkItem::Shutdown => {
-                    info!("Shutting down worker {}", self.num);
-                    return;
+            let (id, m) = match lock(&self.shared.recv).recv() {
+                Ok(m) => m,
+                // Pool has gone away
+                Err(_) => return,
+            };
+            if let WorkItem::Shutdown = m {
+                info!("Shutting down worker {}", self.num);
+                return;
+            }
+
+            let description = m.describe();
+            lock(&self.shared.statuses)[self.num].current =
+                Some((description.clone(), Instant::now()));
+            lock(&self.shared.jobs).started(id, self.num);
+
+            let result = match std::panic::catch_unwind(AssertUnwindSafe(|| self.process(&m))) {
+                Ok(r) => r,
+                Err(payload) => Err(anyhow::anyhow!("Panicked: {}", panic_message(&*payload))),
+            };
+            if let Err(ref e) = result {
+                error!("Worker {}: {} failed - {:?}", self.num, description, e);
+                if let Err(e2) = record_failure(&m, &format!("{:#}", e)) {
+                    error!("Failed to record failure of {} - {:?}", description, e2);
                 }
+            }
+
+            lock(&self.shared.jobs).finished(id, result.as_ref().err().map(|e| format!("{:#}", e)));
+            let mut statuses = lock(&self.shared.statuses);
+            let status = &mut statuses[self.num];
+            status.current = None;
+            status.jobs_done += 1;
+            if result.is_err() {
+                status.jobs_failed += 1;
+            }
+        }
+    }
 
-                WorkItem::Download(ref val) => {
-                    println!("Worker {}: Download {:#?}", self.num, val);
-                    let cfg = crate::config::Config::load();
-                    let db = crate::db::Database::open(&cfg).unwrap();
-
-                    val.set_status(&db, VideoStatus::Downloading).unwrap();
-                    let dl = crate::download::download(&val.info);
-
-                    match dl {
-                        Ok(_) => {
-                            info!("Grabbed {:?} successfully", &val.info);
-                            val.set_status(&db, crate::common::VideoStatus::Grabbed)
-                                .unwrap()
-                        }
-                        Err(e) => {
-                            error!("Error downloading {:?} - {:?}", &val.info, e);
-                            val.set_status(&db, crate::common::VideoStatus::GrabError)
-                                .unwrap();
-                        }
-                    };
+    fn process(&self, item: &WorkItem) -> Result<()> {
+        match item {
+            WorkItem::Shutdown => Ok(()),
+            WorkItem::Download(ref val) => {
+                println!("Worker {}: Download {:#?}", self.num, val);
+                download(&crate::config::Config::load()?, val)
+            }
+            WorkItem::UpdateCheck(ref chan) => {
+                // Cache thumbnails now, so they are ready when the videos are first viewed
+                for url in update_check(chan)? {
+                    self.shared.enqueue(WorkItem::ThumbnailCache(url));
                 }
+                Ok(())
+            }
+            WorkItem::ThumbnailCache(ref url) => crate::thumbnails::fetch(url),
+        }
+    }
+}
+
+/// Download a queued video, retrying transient failures and recording the outcome
+fn download(cfg: &Config, val: &DBVideoInfo) -> Result<()> {
+    let db = crate::db::Database::open(cfg)?;
+
+    // Skip videos which were removed from the queue while waiting
+    let current = DBVideoInfo::get_by_sqlid(&db, val.id)?;
+    if current.status != VideoStatus::Queued {
+        info!(
+            "Skipping {:?} as it is no longer queued (status {:?})",
+            &val.info, current.status
+        );
+        return Ok(());
+    }
+
+    // Quality profile requested for video, otherwise the one for its channel
+    let chan = val.channel(&db)?;
+    let profile_name = current
+        .profile
+        .or_else(|| chan.profile.clone())
+        .unwrap_or_else(|| cfg.default_profile.clone());
+    let profile = match cfg.profile(&profile_name) {
+        Ok(p) => p.clone(),
+        Err(e) => {
+            error!("Cannot download {:?} - {}", &val.info, e);
+            let message = format!("{}", e);
+            val.record_attempt(&db, Some(&message))?;
+            val.set_status(&db, VideoStatus::GrabError)?;
+            notify(cfg, &Event::failed(&chan, val, &message));
+            return Ok(());
+        }
+    };
 
-                WorkItem::UpdateCheck(ref chan) => {
-                    let cfg = crate::config::Config::load();
-                    let db = crate::db::Database::open(&cfg).unwrap();
-                    let last_update = chan.last_update(&db).unwrap();
-                    debug!(
-                        "Checking channel for update {:?} - last update {:?}",
-                        chan, last_update
+    val.set_status(&db, VideoStatus::Downloading)?;
+    let output_dir = crate::layout::output_dir(cfg, &chan, val);
+
+    let started = Instant::now();
+    let mut attempt: u32 = 0;
+    let dl = loop {
+        attempt += 1;
+        let dl = crate::download::download(&val.info, cfg, &profile, &output_dir);
+
+        let (message, transient) = match dl {
+            Ok(_) => break dl,
+            Err(ref e) => match e.downcast_ref::<DownloadFailure>() {
+                Some(f) if f.kind == DownloadErrorKind::Cancelled => break dl,
+                Some(f) => (f.message.clone(), f.kind.is_transient()),
+                None => (format!("{}", e), false),
+            },
+        };
+        val.record_attempt(&db, Some(&message))?;
+
+        if !transient || attempt > cfg.download_retries {
+            break dl;
+        }
+        let delay = retry_delay(cfg.download_retry_delay, attempt);
+        warn!(
+            "Download attempt {} of {:?} failed ({}), retrying in {:?}",
+            attempt, &val.info, message, delay
+        );
+        std::thread::sleep(delay);
+
+        // Stop retrying if cancelled while waiting
+        let current = DBVideoInfo::get_by_sqlid(&db, val.id)?;
+        if current.status != VideoStatus::Downloading {
+            break dl;
+        }
+    };
+
+    // If download was cancelled the status has already been reset
+    let current = DBVideoInfo::get_by_sqlid(&db, val.id)?;
+    if current.status != VideoStatus::Downloading {
+        info!("Download of {:?} was cancelled", &val.info);
+        return Ok(());
+    }
+
+    match dl {
+        Ok(path) => {
+            info!("Grabbed {:?} successfully to {:?}", &val.info, &path);
+            val.record_attempt(&db, None)?;
+            let path = path.map(|p| std::fs::canonicalize(&p).unwrap_or(p));
+            let size = path
+                .as_ref()
+                .and_then(|p| std::fs::metadata(p).ok())
+                .map(|m| m.len())
+                .unwrap_or(0);
+            crate::metrics::download_finished(started.elapsed(), Some(size));
+            if let Some(ref path) = path {
+                val.set_file_path(&db, path.to_str())?;
+
+                if let Err(e) = crate::layout::process(cfg, &db, &chan, val, path) {
+                    error!("Failed to write layout files for {:?} - {:?}", &val.info, e);
+                }
+                if let Err(e) = crate::sidecar::process(&db, val, &chan, path, &profile) {
+                    error!(
+                        "Failed to create sidecar files for {:?} - {:?}",
+                        &val.info, e
                     );
-                    let time_to_update = if let Some(last_update) = last_update {
-                        let now = chrono::Utc::now();
-                        let delta = now - last_update;
-                        delta > chrono::Duration::minutes(60)
-                    } else {
-                        // No laste update, so time to update now
-                        true
-                    };
-
-                    if time_to_update {
-                        info!("Time to update {:?}", &chan);
-                        chan.update(&db).unwrap();
-                    };
                 }
-
-                WorkItem::ThumbnailCache(ref url) => {
-                    let resp = attohttpc::get(&url).send().unwrap();
-                    if !resp.status().is_success() {
-                        error!("Failed to grab thumbnail for {}", &url);
-                    } else {
-                        let ct: String = resp
-                            .headers()
-                            .get(attohttpc::header::CONTENT_TYPE)
-                            .unwrap()
-                            .to_str()
-                            .unwrap()
-                            .into();
-                        let data = resp.bytes().unwrap();
-                        let img = crate::web::Image {
-                            content_type: ct.clone(),
-                            data: data.clone(),
-                        };
-                        crate::web::IMG_CACHE.lock().unwrap().add(&url, img);
-                    }
+            }
+            match crate::hooks::run_hooks(
+                cfg,
+                &chan,
+                val,
+                path.as_deref(),
+                crate::common::VideoStatus::Grabbed,
+            ) {
+                Ok(()) => {
+                    val.set_status(&db, crate::common::VideoStatus::Grabbed)?;
+                    notify(cfg, &Event::grabbed(&chan, val));
+                }
+                Err(e) => {
+                    error!("Post-download hook failed for {:?} - {:?}", &val.info, e);
+                    let message = format!("{}", e);
+                    val.set_last_error(&db, Some(&message))?;
+                    val.set_status(&db, crate::common::VideoStatus::GrabError)?;
+                    notify(cfg, &Event::failed(&chan, val, &message));
                 }
             }
         }
+        Err(e) => {
+            error!("Error downloading {:?} - {:?}", &val.info, e);
+            crate::metrics::download_finished(started.elapsed(), None);
+            val.set_status(&db, crate::common::VideoStatus::GrabError)?;
+            notify(cfg, &Event::failed(&chan, val, &format!("{}", e)));
+        }
+    };
+    Ok(())
+}
+
+/// Add any new videos in a channel, returning the thumbnails of the channel and its new videos
+fn update_check(chan: &Channel) -> Result<Vec<String>> {
+    let cfg = crate::config::Config::load()?;
+    let db = crate::db::Database::open(&cfg)?;
+    let last_update = chan.last_update(&db)?;
+    debug!(
+        "Checking channel for update {:?} - last update {:?}",
+        chan, last_update
+    );
+    let time_to_update = if let Some(last_update) = last_update {
+        let now = chrono::Utc::now();
+        let delta = now - last_update;
+        delta > chrono::Duration::minutes(60)
+    } else {
+        // No laste update, so time to update now
+        true
+    };
+
+    let mut thumbnails = vec![];
+    if time_to_update {
+        info!("Time to update {:?}", &chan);
+        let added = chan.update(&db)?;
+        if !added.is_empty() {
+            notify(&cfg, &Event::new_videos(chan, &added));
+        }
+
+        // Thumbnail may have changed along with the rest of the channel's metadata
+        thumbnails.push(Channel::get_by_sqlid(&db, chan.id)?.thumbnail);
+        thumbnails.extend(added.into_iter().map(|v| v.info.thumbnail_url));
+    };
+    Ok(thumbnails)
+}
+
+/// Store error from a failed job against what it was working on, so it is visible in the
+/// web interface rather than only in the logs
+fn record_failure(item: &WorkItem, message: &str) -> Result<()> {
+    match item {
+        WorkItem::Download(v) => {
+            let cfg = crate::config::Config::load()?;
+            let db = crate::db::Database::open(&cfg)?;
+            v.set_last_error(&db, Some(message))?;
+            v.set_status(&db, VideoStatus::GrabError)?;
+            let chan = v.channel(&db)?;
+            notify(&cfg, &Event::failed(&chan, v, message));
+        }
+        WorkItem::ThumbnailCache(url) => crate::thumbnails::record_failure(url),
+        // Logged by caller, and retried on the next check
+        WorkItem::UpdateCheck(_) | WorkItem::Shutdown => (),
     }
+    Ok(())
 }
 
 pub struct WorkerPool {
-    pool: threadpool::ThreadPool,
+    shared: Arc<Shared>,
     num_workers: usize,
-    sender: mpsc::Sender<WorkItem>,
 }
 
 impl WorkerPool {
     pub fn start() -> Self {
         let num_workers = 4;
-        let pool = threadpool::ThreadPool::new(num_workers);
         let (sender, recv) = mpsc::channel();
-        let recv = Arc::new(Mutex::new(recv));
+        let shared = Arc::new(Shared {
+            recv: Mutex::new(recv),
+            sender: Mutex::new(sender),
+            statuses: Mutex::new((0..num_workers).map(|_| WorkerStatus::default()).collect()),
+            jobs: Mutex::new(JobLog::default()),
+            respawns: Mutex::new(0),
+            pool: threadpool::ThreadPool::new(num_workers),
+        });
 
         // Launch worker threads
         for curnum in 0..num_workers {
-            let w = Worker {
-                recv: recv.clone(),
-                num: curnum,
-            };
-            pool.execute(move || w.run());
+            Worker::spawn(shared.clone(), curnum);
         }
 
         Self {
-            pool,
+            shared,
             num_workers,
-            sender,
         }
     }
 
     pub fn enqueue(&self, item: WorkItem) {
-        self.sender.send(item).unwrap();
+        self.shared.enqueue(item);
+    }
+
+    pub fn health(&self) -> PoolHealth {
+        let statuses = lock(&self.shared.statuses);
+        let workers: Vec<WorkerHealth> = statuses
+            .iter()
+            .enumerate()
+            .map(|(num, s)| WorkerHealth {
+                num,
+                job: s.current.as_ref().map(|(d, _)| d.clone()),
+                busy_secs: s.current.as_ref().map(|(_, t)| t.elapsed().as_secs()),
+                jobs_done: s.jobs_done,
+                jobs_failed: s.jobs_failed,
+            })
+            .collect();
+        let active = workers.iter().filter(|w| w.job.is_some()).count();
+        PoolHealth {
+            idle: workers.len() - active,
+            active,
+            alive: self.shared.pool.active_count(),
+            workers,
+            respawns: *lock(&self.shared.respawns),
+        }
+    }
+
+    pub fn queue(&self) -> QueueReport {
+        let jobs = lock(&self.shared.jobs);
+        QueueReport {
+            pending: jobs.pending.iter().map(JobReport::from).collect(),
+            running: jobs.running.iter().map(JobReport::from).collect(),
+            finished: jobs.finished.iter().map(JobReport::from).collect(),
+        }
     }
 
     /// Completes all queued work then stops workers
@@ -142,20 +563,132 @@ impl Drop for WorkerPool {
     fn drop(&mut self) {
         debug!("Dropping WorkerPool, starting shutdown");
         info!("Commencing worker pool shutdown");
+        // Jobs can queue more work (e.g update checks caching thumbnails), which would end up
+        // behind the shutdown messages if they were sent straight away
+        loop {
+            {
+                let jobs = lock(&self.shared.jobs);
+                if jobs.pending.is_empty() && jobs.running.is_empty() {
+                    break;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
         for _ in 0..self.num_workers {
-            self.sender.send(WorkItem::Shutdown).unwrap();
+            self.enqueue(WorkItem::Shutdown);
         }
         debug!("Joining worker pool");
-        self.pool.join();
+        self.shared.pool.join();
     }
 }
 
 pub fn main() -> Result<()> {
-    let cfg = crate::config::Config::load();
+    let cfg = crate::config::Config::load()?;
     let db = crate::db::Database::open(&cfg)?;
     let v = crate::db::DBVideoInfo::get_by_sqlid(&db, 1)?;
+    v.set_status(&db, VideoStatus::Queued)?;
 
     let p = WorkerPool::start();
     p.enqueue(WorkItem::Download(v));
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_download_work_item() -> Result<()> {
+        // Point config at temporary location, using fake downloader
+        let dir = std::env::temp_dir().join(format!("vidl-test-worker-{}", std::process::id()));
+        let mut cfg = Config::load_from(&dir.join("config"))?;
+        cfg.download_dir = dir.join("download");
+        cfg.downloader = crate::download::DownloaderKind::Fake;
+        cfg.downloader_path = None;
+
+        let db = crate::db::Database::open(&cfg)?;
+        let cid = crate::common::ChannelID::Youtube(crate::common::YoutubeID {
+            id: "UCUBfKCp83QT19JCUekEdxOQ".into(),
+        });
+        let chan = Channel::create(&db, &cid, "test channel", "http://example.com/thumb.jpg")?;
+        let video = chan.add_video(
+            &db,
+            &crate::youtube::VideoInfo {
+                id: "abc123".into(),
+                url: "http://youtube.com/watch?v=abc123".into(),
+                title: "A title".into(),
+                description: "".into(),
+                thumbnail_url: "http://example.com/vidthumb.jpg".into(),
+                published_at: chrono::Utc::now(),
+            },
+        )?;
+
+        video.set_status(&db, VideoStatus::Queued)?;
+        download(&cfg, &video)?;
+
+        let video = DBVideoInfo::get_by_sqlid(&db, video.id)?;
+        match video.status {
+            VideoStatus::Grabbed => (),
+            other => panic!("Unexpected status {:?}", other),
+        }
+        assert_eq!(video.attempts, 1);
+        assert!(std::path::Path::new(&video.file_path.unwrap()).exists());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_runs_queued_follow_up_work() {
+        let p = WorkerPool::start();
+        let shared = p.shared.clone();
+
+        // Pretend a job is running which queues more work just before finishing
+        let id = lock(&shared.jobs).queued(&WorkItem::ThumbnailCache("".into()));
+        lock(&shared.jobs).started(id, 0);
+        let job = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            shared.enqueue(WorkItem::ThumbnailCache("".into()));
+            lock(&shared.jobs).finished(id, None);
+        });
+        let shared = p.shared.clone();
+        p.stop();
+        job.join().unwrap();
+
+        let jobs = lock(&shared.jobs);
+        assert!(jobs.pending.is_empty());
+        assert_eq!(jobs.finished.len(), 2);
+    }
+
+    #[test]
+    fn test_health() {
+        let p = WorkerPool::start();
+        p.enqueue(WorkItem::ThumbnailCache("".into()));
+        for _ in 0..100 {
+            if p.health().workers.iter().map(|w| w.jobs_done).sum::<u64>() == 1 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let health = p.health();
+        assert_eq!(health.workers.len(), 4);
+        assert_eq!(health.idle, 4);
+        assert_eq!(health.active, 0);
+        assert_eq!(health.alive, 4);
+        assert_eq!(health.respawns, 0);
+        assert_eq!(health.workers.iter().map(|w| w.jobs_done).sum::<u64>(), 1);
+
+        let queue = p.queue();
+        assert!(queue.pending.is_empty());
+        assert!(queue.running.is_empty());
+        assert_eq!(queue.finished.len(), 1);
+        assert_eq!(queue.finished[0].kind, "thumbnail");
+        assert!(queue.finished[0].worker.is_some());
+        assert!(queue.finished[0].error.is_none());
+
+        assert_eq!(
+            panic_message(&*std::panic::catch_unwind(|| panic!("oops")).unwrap_err()),
+            "oops"
+        );
+    }
+}
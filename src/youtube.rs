@@ -108,7 +108,7 @@ impl std::fmt::Debug for VideoInfo {
 fn request_data<T: serde::de::DeserializeOwned + std::fmt::Debug>(url: &str) -> Result<T> {
     fn subreq<T: serde::de::DeserializeOwned + std::fmt::Debug>(url: &str) -> Result<T> {
         debug!("Retrieving URL {}", &url);
-        let resp = attohttpc::get(&url).send()?;
+        let resp = attohttpc::get(url).send()?;
         let text = resp.text()?;
         trace!("Raw response: {}", &text);
         let data: T = serde_json::from_str(&text)
@@ -139,7 +139,7 @@ pub struct YoutubeQuery<'a> {
 }
 
 impl<'a> YoutubeQuery<'a> {
-    pub fn new(chan_id: &YoutubeID) -> YoutubeQuery {
+    pub fn new(chan_id: &YoutubeID) -> YoutubeQuery<'_> {
         YoutubeQuery { chan_id }
     }
 
@@ -180,7 +180,7 @@ impl<'a> YoutubeQuery<'a> {
                     title: d.title.clone(),
                     description: d.description.clone(),
                     thumbnail_url: d.video_thumbnails.first().unwrap().url.clone(),
-                    published_at: chrono::Utc.timestamp(d.published, 0),
+                    published_at: chrono::Utc.timestamp_opt(d.published, 0).unwrap(),
                 })
                 .collect();
 
@@ -192,7 +192,7 @@ impl<'a> YoutubeQuery<'a> {
         let mut completed = false;
         let mut current_items: VecDeque<VideoInfo> = VecDeque::new();
 
-        let it = std::iter::from_fn(move || -> Option<Result<VideoInfo>> {
+        std::iter::from_fn(move || -> Option<Result<VideoInfo>> {
             if completed {
                 return None;
             }
@@ -213,7 +213,7 @@ impl<'a> YoutubeQuery<'a> {
                         Some(Err(e))
                     }
                     Ok(new_items) => {
-                        if new_items.len() == 0 {
+                        if new_items.is_empty() {
                             // No more items, stop iterator
                             None
                         } else {
@@ -224,8 +224,7 @@ impl<'a> YoutubeQuery<'a> {
                 };
                 nextup
             }
-        });
-        it
+        })
     }
 }
 
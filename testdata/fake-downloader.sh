@@ -0,0 +1,45 @@
+#!/bin/sh
+# Stand-in for youtube-dl used by tests, so downloads can be exercised without network access.
+# Understands just enough of youtube-dl's arguments to write a small dummy file to the
+# `--output` template, printing youtube-dl style progress. URL's containing "fail" produce an
+# error instead.
+
+output="%(id)s.%(ext)s"
+url=""
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -o|--output)
+            output="$2"
+            shift
+            ;;
+    esac
+    # Video URL is always the final argument
+    url="$1"
+    shift
+done
+
+id="${url##*v=}"
+
+case "$url" in
+    *fail*)
+        echo "ERROR: Video unavailable" >&2
+        exit 1
+        ;;
+esac
+
+dest=$(printf '%s' "$output" | sed \
+    -e "s/%(id)s/$id/g" \
+    -e "s/%(ext)s/mp4/g" \
+    -e "s/%(title)s/Fake_video/g" \
+    -e "s/%(uploader)s/Fake_uploader/g" \
+    -e "s/%(upload_date)s/20200101/g" \
+    -e "s/%([a-z_]*)s/NA/g")
+
+mkdir -p "$(dirname "$dest")"
+
+echo "[youtube] $id: Downloading webpage"
+echo "[download] Destination: $dest"
+for pct in 0.0 50.0 100.0; do
+    echo "[download] $pct% of 1.00KiB at 1.00KiB/s ETA 00:00"
+done
+head -c 1024 /dev/zero > "$dest"